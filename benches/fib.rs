@@ -0,0 +1,48 @@
+// Interpreter and virtual machine benchmarks over example/fib.icml, with the libraries it includes in the example tests.
+//   cargo bench --bench fib
+
+use std::fs;
+use std::io::BufReader;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ironcamel::interpreter::EvalOptions;
+use ironcamel::parser::ProgramAST;
+use ironcamel::pipeline::{PipelineOptions, SourceFile};
+use ironcamel::value::Value;
+
+const INCLUDES: [&str; 2] = ["include/core.icml", "include/stdlib.icml"];
+
+// example/fib.icml, computing the `n`-th Fibonacci number instead of the 12th
+fn build_fib_program(n: i64) -> ProgramAST {
+    let mut source_vec = Vec::new();
+    for lib in INCLUDES {
+        source_vec.push(SourceFile { path: lib.to_owned(), code: fs::read_to_string(lib).unwrap(), is_library: true });
+    }
+    let code = fs::read_to_string("example/fib.icml").unwrap()
+        .replace("let n = 12;", &format!("let n = {};", n));
+    source_vec.push(SourceFile { path: String::from("example/fib.icml"), code, is_library: false });
+    ironcamel::pipeline::build_program(&source_vec, &PipelineOptions::default())
+        .unwrap_or_else(|errors| panic!("{:?}", errors))
+        .ast
+}
+
+fn run(ast: &ProgramAST, options: &EvalOptions) -> Value {
+    ironcamel::interpreter::eval_with_stdio_and_options(ast,
+                                                        Box::new(BufReader::new(std::io::empty())),
+                                                        Box::new(std::io::sink()),
+                                                        options)
+        .unwrap()
+}
+
+fn bench_fib(c: &mut Criterion) {
+    let vm = EvalOptions { vm: true, ..EvalOptions::default() };
+    for n in [12, 20] {
+        let ast = build_fib_program(n);
+        c.bench_function(&format!("fib.icml n={}", n), |b| b.iter(|| run(black_box(&ast), &EvalOptions::default())));
+        c.bench_function(&format!("fib.icml n={} --vm", n), |b| b.iter(|| run(black_box(&ast), &vm)));
+    }
+    c.bench_function("fib.icml build", |b| b.iter(|| build_fib_program(black_box(12))));
+}
+
+criterion_group!(benches, bench_fib);
+criterion_main!(benches);
@@ -0,0 +1,424 @@
+// Compiled programs saved by `ironcamel build` as .icmlc files, which `ironcamel --run` executes on the
+// virtual machine without tokenizing, parsing, checking or compiling anything again.
+// The layout, every integer little endian:
+//   magic             b"ICMLC\0"
+//   u32               FORMAT_VERSION
+//   string            the version of ironcamel which built it
+//   sources           path and hash of every source file, to tell whether the artifact is stale
+//   program           the BytecodeProgram
+// A string is its u32 length followed by UTF-8 bytes, a vector its u32 length followed by the items.
+// Ops change from one release to the next, so an artifact only runs on the version which built it.
+
+use crate::builtin;
+use crate::bytecode::{BytecodeProgram, Capture, IoOp, Op, Proto};
+use crate::parser::FileOpenAst;
+use crate::pipeline::SourceFile;
+use crate::value::Value;
+
+pub const ARTIFACT_EXTENSION: &str = "icmlc";
+// Bumped whenever the layout changes
+pub const FORMAT_VERSION: u32 = 4;
+const MAGIC: &[u8; 6] = b"ICMLC\0";
+const IRONCAMEL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub struct Artifact {
+    pub sources: Vec<SourceDigest>,
+    pub program: BytecodeProgram,
+}
+
+// A source file the artifact was built from
+#[derive(Debug, PartialEq)]
+pub struct SourceDigest {
+    pub path: String,
+    pub hash: u64,
+}
+
+impl SourceDigest {
+    pub fn of(source: &SourceFile) -> SourceDigest {
+        SourceDigest { path: source.path.to_owned(), hash: fnv1a(source.code.as_bytes()) }
+    }
+}
+
+// FNV-1a, unlike DefaultHasher it's the same on every build
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn is_artifact_path(path: &str) -> bool {
+    std::path::Path::new(path).extension().is_some_and(|ext| ext == ARTIFACT_EXTENSION)
+}
+
+impl Artifact {
+    // The sources changed since the artifact was built.
+    // A missing source doesn't count, an artifact can be run without them
+    pub fn stale_sources(&self) -> Vec<&str> {
+        self.sources.iter()
+            .filter(|s| std::fs::read(&s.path).is_ok_and(|code| fnv1a(&code) != s.hash))
+            .map(|s| s.path.as_str())
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.0.extend_from_slice(MAGIC);
+        w.u32(FORMAT_VERSION);
+        w.str(IRONCAMEL_VERSION);
+        w.usize(self.sources.len());
+        for s in &self.sources {
+            w.str(&s.path);
+            w.u64(s.hash);
+        }
+        w.program(&self.program);
+        w.0
+    }
+
+    // Fails on anything but an artifact of this very version
+    pub fn from_bytes(bytes: &[u8]) -> Result<Artifact, String> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(String::from("not an IronCamel artifact"));
+        }
+        let mut r = Reader { bytes, pos: MAGIC.len() };
+        let format = r.u32()?;
+        if format != FORMAT_VERSION {
+            return Err(format!("artifact format {} is not supported, expected {}, rebuild it", format, FORMAT_VERSION));
+        }
+        let version = r.str()?;
+        if version != IRONCAMEL_VERSION {
+            return Err(format!("built by ironcamel {}, this is {}, rebuild it", version, IRONCAMEL_VERSION));
+        }
+        let sources = r.vec(|r| Ok(SourceDigest { path: r.str()?, hash: r.u64()? }))?;
+        let program = r.program()?;
+        if r.pos != bytes.len() {
+            return Err(String::from("corrupted artifact: trailing bytes"));
+        }
+        check_program(&program)?;
+        Ok(Artifact { sources, program })
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, x: u8) {
+        self.0.push(x);
+    }
+
+    fn u32(&mut self, x: u32) {
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.0.extend_from_slice(&x.to_le_bytes());
+    }
+
+    fn usize(&mut self, x: usize) {
+        self.u32(u32::try_from(x).expect("Too large for an artifact"));
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn program(&mut self, program: &BytecodeProgram) {
+        self.usize(program.functions.len());
+        program.functions.iter().for_each(|p| self.proto(p));
+        self.usize(program.closures.len());
+        program.closures.iter().for_each(|p| self.proto(p));
+        match &program.main {
+            Some(main) => {
+                self.u8(1);
+                self.proto(main);
+            },
+            None => self.u8(0)
+        }
+        self.usize(program.tests.len());
+        program.tests.iter().for_each(|p| self.proto(p));
+        self.usize(program.constants.len());
+        program.constants.iter().for_each(|c| self.constant(c));
+        self.usize(program.io.len());
+        program.io.iter().for_each(|io| self.io(io));
+        self.usize(program.sites.len());
+        program.sites.iter().for_each(|s| self.str(s));
+    }
+
+    fn proto(&mut self, proto: &Proto) {
+        self.str(&proto.name);
+        self.str(&proto.location);
+        self.usize(proto.arity);
+        self.usize(proto.locals);
+        self.usize(proto.captures.len());
+        for capture in &proto.captures {
+            match *capture {
+                Capture::Local(slot) => { self.u8(0); self.usize(slot) },
+                Capture::Upvalue(i) => { self.u8(1); self.usize(i) },
+            }
+        }
+        self.usize(proto.code.len());
+        proto.code.iter().for_each(|op| self.op(op));
+    }
+
+    fn op(&mut self, op: &Op) {
+        match *op {
+            Op::Const(i) => { self.u8(0); self.usize(i) },
+            Op::Local(slot) => { self.u8(1); self.usize(slot) },
+            Op::Upvalue(i) => { self.u8(2); self.usize(i) },
+            Op::Function(id) => { self.u8(3); self.usize(id) },
+            Op::Builtin(name) => { self.u8(4); self.str(name) },
+            Op::Closure(id) => { self.u8(5); self.usize(id) },
+            Op::SetLocal(slot) => { self.u8(6); self.usize(slot) },
+            Op::JumpIfFalse(target) => { self.u8(7); self.usize(target) },
+            Op::Jump(target) => { self.u8(8); self.usize(target) },
+            Op::CheckCallable => self.u8(9),
+            Op::Call(argc, site) => { self.u8(10); self.usize(argc); self.usize(site) },
+            Op::CallFunction(id, argc, site) => { self.u8(11); self.usize(id); self.usize(argc); self.usize(site) },
+            Op::CallBuiltin(name, argc, site) => { self.u8(12); self.str(name); self.usize(argc); self.usize(site) },
+            Op::TailCall(argc, site) => { self.u8(13); self.usize(argc); self.usize(site) },
+            Op::TailCallFunction(id, argc, site) => { self.u8(14); self.usize(id); self.usize(argc); self.usize(site) },
+            Op::Return => self.u8(15),
+            Op::Io(i) => { self.u8(16); self.usize(i) },
+        }
+    }
+
+    // Only literals end up in the constants
+    fn constant(&mut self, value: &Value) {
+        match value {
+            Value::Int(x) => { self.u8(0); self.u64(*x as u64) },
+            // In decimal, as it was written in the source
+            Value::BigInt(x) => { self.u8(3); self.str(&x.to_string()) },
+            Value::Bool(x) => { self.u8(1); self.u8(*x as u8) },
+            Value::Str(s) => { self.u8(2); self.str(s) },
+            _ => panic!("Not a constant: {}", value.summary())
+        }
+    }
+
+    fn io(&mut self, io: &IoOp) {
+        match io {
+            IoOp::Read { procedure, file_handler, variable } => {
+                self.u8(0);
+                self.str(procedure);
+                self.str(file_handler);
+                self.str(variable);
+            },
+            IoOp::Write { procedure, file_handler } => {
+                self.u8(1);
+                self.str(procedure);
+                self.str(file_handler);
+            },
+            IoOp::FileOpen(fo) => {
+                self.u8(2);
+                self.str(&fo.impure_procedure_name);
+                self.str(&fo.file_handler);
+                self.str(&fo.file_path);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < n {
+            return Err(String::from("corrupted artifact: unexpected end of file"));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn usize(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| String::from("corrupted artifact: invalid UTF-8"))
+    }
+
+    fn vec<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        let len = self.usize()?;
+        // Not trusting len for the capacity, a corrupted file could ask for anything
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn program(&mut self) -> Result<BytecodeProgram, String> {
+        let functions = self.vec(Self::proto)?;
+        let closures = self.vec(Self::proto)?;
+        let main = match self.u8()? {
+            0 => None,
+            1 => Some(self.proto()?),
+            tag => return Err(corrupted("main", tag))
+        };
+        let tests = self.vec(Self::proto)?;
+        let constants = self.vec(Self::constant)?;
+        let io = self.vec(Self::io)?;
+        let sites = self.vec(Self::str)?;
+        Ok(BytecodeProgram { functions, closures, main, tests, constants, io, sites })
+    }
+
+    fn proto(&mut self) -> Result<Proto, String> {
+        let name = self.str()?;
+        let location = self.str()?;
+        let arity = self.usize()?;
+        let locals = self.usize()?;
+        let captures = self.vec(|r| match r.u8()? {
+            0 => Ok(Capture::Local(r.usize()?)),
+            1 => Ok(Capture::Upvalue(r.usize()?)),
+            tag => Err(corrupted("capture", tag))
+        })?;
+        let code = self.vec(Self::op)?;
+        Ok(Proto { name, location, arity, locals, captures, code })
+    }
+
+    fn op(&mut self) -> Result<Op, String> {
+        Ok(match self.u8()? {
+            0 => Op::Const(self.usize()?),
+            1 => Op::Local(self.usize()?),
+            2 => Op::Upvalue(self.usize()?),
+            3 => Op::Function(self.usize()?),
+            4 => Op::Builtin(self.builtin()?),
+            5 => Op::Closure(self.usize()?),
+            6 => Op::SetLocal(self.usize()?),
+            7 => Op::JumpIfFalse(self.usize()?),
+            8 => Op::Jump(self.usize()?),
+            9 => Op::CheckCallable,
+            10 => Op::Call(self.usize()?, self.usize()?),
+            11 => Op::CallFunction(self.usize()?, self.usize()?, self.usize()?),
+            12 => Op::CallBuiltin(self.builtin()?, self.usize()?, self.usize()?),
+            13 => Op::TailCall(self.usize()?, self.usize()?),
+            14 => Op::TailCallFunction(self.usize()?, self.usize()?, self.usize()?),
+            15 => Op::Return,
+            16 => Op::Io(self.usize()?),
+            tag => return Err(corrupted("op", tag))
+        })
+    }
+
+    fn builtin(&mut self) -> Result<&'static str, String> {
+        let name = self.str()?;
+        builtin::find_builtin(&name).ok_or_else(|| format!("corrupted artifact: no builtin {}", name))
+    }
+
+    fn constant(&mut self) -> Result<Value, String> {
+        Ok(match self.u8()? {
+            0 => Value::Int(self.u64()? as i64),
+            3 => Value::integer(self.str()?.parse().map_err(|_| String::from("corrupted artifact: not an integer"))?),
+            1 => Value::Bool(self.u8()? != 0),
+            2 => Value::str(&self.str()?),
+            tag => return Err(corrupted("constant", tag))
+        })
+    }
+
+    fn io(&mut self) -> Result<IoOp, String> {
+        Ok(match self.u8()? {
+            0 => IoOp::Read { procedure: self.str()?, file_handler: self.str()?, variable: self.str()? },
+            1 => IoOp::Write { procedure: self.str()?, file_handler: self.str()? },
+            2 => IoOp::FileOpen(FileOpenAst {
+                impure_procedure_name: self.str()?,
+                file_handler: self.str()?,
+                file_path: self.str()?
+            }),
+            tag => return Err(corrupted("IO operation", tag))
+        })
+    }
+}
+
+fn corrupted(what: &str, tag: u8) -> String {
+    format!("corrupted artifact: unknown {} tag {}", what, tag)
+}
+
+// The VM trusts the compiler, so indices read from a file are checked once here rather than on every op
+fn check_program(program: &BytecodeProgram) -> Result<(), String> {
+    let protos = program.functions.iter()
+        .chain(&program.closures)
+        .chain(&program.main)
+        .chain(&program.tests);
+    for proto in protos {
+        let in_range = |op: &Op| match *op {
+            Op::Const(i) => i < program.constants.len(),
+            Op::Local(slot) | Op::SetLocal(slot) => slot < proto.locals,
+            Op::Upvalue(i) => i < proto.captures.len(),
+            Op::Function(id) => id < program.functions.len(),
+            Op::CallFunction(id, _, site) | Op::TailCallFunction(id, _, site) => {
+                id < program.functions.len() && site < program.sites.len()
+            },
+            Op::Call(_, site) | Op::CallBuiltin(_, _, site) | Op::TailCall(_, site) => site < program.sites.len(),
+            Op::Closure(id) => id < program.closures.len(),
+            Op::JumpIfFalse(target) | Op::Jump(target) => target < proto.code.len(),
+            Op::Io(i) => i < program.io.len(),
+            Op::Builtin(_) | Op::CheckCallable | Op::Return => true,
+        };
+        if proto.arity > proto.locals || !proto.code.iter().all(in_range) {
+            return Err(format!("corrupted artifact: invalid code in {}", proto.name));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::artifact::{Artifact, SourceDigest, FORMAT_VERSION};
+    use crate::bytecode::compile_program;
+    use crate::lower::lower_program;
+    use crate::pipeline::{test_support, SourceFile};
+
+    fn build(code: &str) -> Artifact {
+        let program = compile_program(&lower_program(&test_support::build(code)));
+        let source = SourceFile { path: String::from("does/not/exist.icml"), code: code.to_owned(), is_library: false };
+        Artifact { sources: vec![SourceDigest::of(&source)], program }
+    }
+
+    #[test]
+    fn round_trip() {
+        let code = "fn f(a, b) { let c = +(a, b); let g = |x| { +(x, c) }; if ==(a, 0) then { g(1) } else { f(-(a, 1), b) } }\n\
+                    fn main() { writeline@stdout << \"hi\"; writeline@stdout << 123456789012345678901234567890; f(3, list(1, 2)) }";
+        let artifact = build(code);
+        let bytes = artifact.to_bytes();
+        let loaded = Artifact::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.sources, artifact.sources);
+        assert_eq!(loaded.to_bytes(), bytes);
+        assert!(loaded.program.constants.iter().any(|c| c.to_string() == "123456789012345678901234567890"));
+        // A missing source isn't stale
+        assert!(loaded.stale_sources().is_empty());
+    }
+
+    #[test]
+    fn rejects_other_versions_and_corrupted_files() {
+        let bytes = build("fn main() { 1 }").to_bytes();
+        assert_eq!(Artifact::from_bytes(b"fn main() { 1 }").err().unwrap(), "not an IronCamel artifact");
+
+        let mut other_format = bytes.clone();
+        other_format[6..10].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(Artifact::from_bytes(&other_format).err().unwrap().starts_with("artifact format"));
+
+        // The version string follows its length
+        let mut other_version = bytes.clone();
+        other_version[14] = b'9';
+        assert!(Artifact::from_bytes(&other_version).err().unwrap().starts_with("built by ironcamel 9"));
+
+        assert!(Artifact::from_bytes(&bytes[..bytes.len() - 1]).err().unwrap().contains("unexpected end of file"));
+    }
+}
@@ -4,375 +4,532 @@
 use std::io::{BufRead, Write};
 use std::rc::Rc;
 use log::debug;
-use crate::debug_output::build_expr_debug_strings;
-use crate::expr::ExprAST;
+use num_bigint::BigInt;
+use num_traits::{One, Signed, Zero};
 use crate::interpreter::{GlobalState, IroncamelFileInfo};
+use crate::trace::IoEvent;
+use crate::native::Natives;
+use crate::value::{compare_values, IroncamelLinkedList, LazyCons, Value, values_equal};
 
-pub const IRONCAMEL_BUILTIN_FUNCTIONS: &[&str; 7] = &["cons", "hd", "tl", "list", "is_empty",
-    "atoi", "strtok"];
-pub const ARITHMETIC_OPERATORS: &[&str; 8] = &["<=", ">=", "+", "-", "*", "==", ">", "<", ];
-#[allow(dead_code)]
+pub const IRONCAMEL_BUILTIN_FUNCTIONS: &[&str; 32] = &["cons", "hd", "tl", "list", "is_empty",
+    "atoi", "strtok", "assert_eq", "not",
+    "abs", "min", "max", "pow", "wrapping_add", "wrapping_sub", "wrapping_mul", "wrapping_pow",
+    "concat", "str_len", "substr", "char_at", "index_of", "starts_with", "ends_with", "trim", "upper", "lower",
+    "replace", "itoa", "to_string", "split", "join"];
+pub const ARITHMETIC_OPERATORS: &[&str; 11] = &["<=", ">=", "+", "-", "*", "/", "%", "==", "!=", ">", "<", ];
+// Not builtins but special forms: the right operand of && and || is only evaluated when needed.
+// They are turned into if expressions by lower.rs, so they can't be used as values
+pub const LOGICAL_OPERATORS: &[&str; 3] = &["&&", "||", "!"];
 pub const IO_OPERATIONS: &[&str; 5] = &["readstr", "writeline", "writelist", "fopen_read", "fopen_write"];
+// The other arithmetic grows a big integer by a few bits per step, pow by as many as it likes
+pub const MAX_POW_BITS: u64 = 1 << 20;
 
-pub(crate) fn perform_read(method_name:&str, file_handler: &str, global_state: &mut GlobalState) -> ExprAST {
-    let fop = global_state.open_file_list.get_mut(file_handler).unwrap();
+// None if the builtin receives a variable number of parameters
+pub fn builtin_arity(func_name: &str) -> Option<usize> {
+    known_arity(func_name).unwrap_or_else(|| panic!("Builtin function ({}) not found", func_name))
+}
+
+// None if it's not one of the builtins here, but maybe a native
+fn known_arity(func_name: &str) -> Option<Option<usize>> {
+    if ARITHMETIC_OPERATORS.contains(&func_name) {
+        return Some(Some(2));
+    }
+    match func_name {
+        "list" => Some(None),
+        "hd" | "tl" | "is_empty" | "atoi" | "not" | "!" => Some(Some(1)),
+        "cons" | "strtok" | "assert_eq" | "&&" | "||" => Some(Some(2)),
+        "str_len" | "trim" | "upper" | "lower" | "itoa" | "to_string" | "abs" => Some(Some(1)),
+        "min" | "max" | "pow" | "wrapping_add" | "wrapping_sub" | "wrapping_mul" | "wrapping_pow" => Some(Some(2)),
+        "concat" | "char_at" | "index_of" | "starts_with" | "ends_with" | "split" | "join" => Some(Some(2)),
+        "substr" | "replace" => Some(Some(3)),
+        _ => None
+    }
+}
+
+// Their params are taken as they are, even thunks not evaluated yet, so that a list may be infinite
+// in the lazy mode. The other builtins receive evaluated params
+pub(crate) fn is_lazy_builtin(func_name: &str) -> bool {
+    matches!(func_name, "cons" | "list")
+}
+
+// They get their params without any thunk inside in the lazy mode, natives included
+pub(crate) fn looks_into_lists(func_name: &str) -> bool {
+    matches!(func_name, "assert_eq" | "==" | "!=" | "<" | ">" | "<=" | ">=" | "to_string" | "join")
+        || known_arity(func_name).is_none()
+}
+
+// The list nodes a builtin allocated for its result, counted against Limits.max_memory
+pub(crate) fn allocated_list_nodes(func_name: &str, result: &Value) -> usize {
+    match (func_name, result) {
+        ("cons", _) => 1,
+        ("list" | "strtok" | "split", Value::List(l)) => l.len,
+        _ => 0
+    }
+}
+
+// The builtin's own name, which outlives the program
+pub(crate) fn find_builtin(func_name: &str) -> Option<&'static str> {
+    ARITHMETIC_OPERATORS.iter()
+        .chain(IRONCAMEL_BUILTIN_FUNCTIONS.iter())
+        .find(|b| **b == func_name)
+        .copied()
+}
+
+// Errors are reported as runtime errors of the IO statement
+pub(crate) fn perform_read(method_name:&str, file_handler: &str, variable: &str,
+                           global_state: &mut GlobalState) -> Result<Value, String> {
+    if method_name == "env" {
+        return read_env(variable, global_state).map(|s| Value::str(&s));
+    }
+    let result = match global_state.replaying() {
+        Some(trace) => trace.replay_read(file_handler)?,
+        None => read(method_name, file_handler, global_state)
+    };
+    if let Some(trace) = global_state.recording() {
+        trace.record_event(IoEvent::Read { handler: file_handler.to_owned(), result: result.clone() });
+    }
+    result.map(|s| Value::str(&s))
+}
+
+// An unset environment variable reads as an empty string
+fn read_env(name: &str, global_state: &mut GlobalState) -> Result<String, String> {
+    let value = match global_state.replaying() {
+        Some(trace) => trace.replay_env(name)?,
+        None => global_state.io.env_var(name).unwrap_or_default()
+    };
+    if let Some(trace) = global_state.recording() {
+        trace.record_event(IoEvent::Env { name: name.to_owned(), value: value.to_owned() });
+    }
+    Ok(value)
+}
+
+fn read(method_name:&str, file_handler: &str, global_state: &mut GlobalState) -> Result<String, String> {
+    let fop = find_file(file_handler, global_state)?;
     match method_name {
         "readstr" => {
             let mut s = String::new();
             match fop {
                 IroncamelFileInfo::FileRead(buf) => {
-                    let _ = buf.read_line(&mut s);
+                    buf.read_line(&mut s).map_err(|e| format!("Read from {} failed: {}", file_handler, e))?;
+                }
+                IroncamelFileInfo::FileWrite(_) | IroncamelFileInfo::Stdout(_) => {
+                    return Err(format!("Can't read from {}, it is opened for writing", file_handler));
                 }
-                IroncamelFileInfo::FileWrite(_) | IroncamelFileInfo::Stdout => { panic!() }
-                IroncamelFileInfo::Stdin => {
+                IroncamelFileInfo::Stdin(input) => {
                     let mut t = String::new();
-                    std::io::stdin().read_line(&mut t).unwrap();
+                    input.read_line(&mut t).map_err(|e| format!("Read from {} failed: {}", file_handler, e))?;
                     s = t.trim_end().to_owned();
                 }
             };
-            let expr = ExprAST::StringLiteral(s);
-            expr
+            Ok(s)
         },
         _ => panic!("No such write function ({})", method_name)
     }
 }
 
-pub fn perform_write(method_name:&str, file_handler: &str, data:&ExprAST, global_state: &mut GlobalState) {
-    let fop: &mut IroncamelFileInfo = global_state.open_file_list.get_mut(file_handler).unwrap();
-    match method_name {
-        "writeline" => writeline(data, fop),
-        "writelist" => writelist(data, fop),
+// The text is written as far as it could be formatted, then the formatting error is reported
+pub fn perform_write(method_name:&str, file_handler: &str, data: &Value, global_state: &mut GlobalState) -> Result<(), String> {
+    let mut text = String::new();
+    let formatted = match method_name {
+        "writeline" => writeline(data, &mut text),
+        "writelist" => writelist(data, &mut text),
         _ => panic!("No such write function ({})", method_name)
+    };
+    if let Some(trace) = global_state.replaying() {
+        trace.replay_write(file_handler, &text)?;
+    }
+    let written = find_file(file_handler, global_state).and_then(|fop| write_internal(&text, fop));
+    if let Some(trace) = global_state.recording() {
+        trace.record_event(IoEvent::Write { handler: file_handler.to_owned(), text });
     }
+    written?;
+    formatted
 }
 
-fn writelist(list: &ExprAST, fop: &mut IroncamelFileInfo) {
-    let mut list = match  list {
-        ExprAST::List(l) => l,
-        _ => panic!("Expect a list, got {:?}", build_expr_debug_strings(list)),
+fn find_file<'a>(file_handler: &str, global_state: &'a mut GlobalState) -> Result<&'a mut IroncamelFileInfo, String> {
+    global_state.open_file_list.get_mut(file_handler)
+        .ok_or_else(|| format!("File handler ({}) is not opened", file_handler))
+}
+
+fn writelist(list: &Value, out: &mut String) -> Result<(), String> {
+    let list = match list {
+        Value::List(l) => l,
+        _ => return Err(format!("Expect a list, got {}", list.summary())),
     };
-    while list.len > 0 {
-        write(&list.value, fop);
-        write_internal(" ", fop);
-        list = &*match &list.next {
-            Some(l) => l,
-            None => break
-        }
+    for v in list.iter() {
+        write(v, out)?;
+        out.push(' ');
     }
-    write_internal("\n", fop);
+    out.push('\n');
+    Ok(())
 }
 
-fn write_internal(s: &str, fop: &mut IroncamelFileInfo) {
+fn write_internal(s: &str, fop: &mut IroncamelFileInfo) -> Result<(), String> {
     match fop {
         IroncamelFileInfo::FileWrite(fs) => {
             let _ = fs.write_all(s.as_ref());
         },
-        IroncamelFileInfo::Stdout => {
-            print!("{}", s);
+        IroncamelFileInfo::Stdout(out) => {
+            let _ = out.write_all(s.as_ref());
         },
-        IroncamelFileInfo::FileRead(_) | IroncamelFileInfo::Stdin => {panic!()}
-
+        IroncamelFileInfo::FileRead(_) | IroncamelFileInfo::Stdin(_) => {
+            return Err(String::from("Can't write to a file opened for reading"));
+        }
     }
+    Ok(())
 }
-fn write(e: &ExprAST, fop: &mut IroncamelFileInfo) {
-    match e {
-        ExprAST::Int(x) => write_internal(&x.to_string(), fop),
-        ExprAST::Bool(x) => {
-            if *x {write_internal("true", fop)} else {write_internal("false", fop)}
+fn write(v: &Value, out: &mut String) -> Result<(), String> {
+    match v {
+        Value::Int(x) => out.push_str(&x.to_string()),
+        Value::BigInt(x) => out.push_str(&x.to_string()),
+        Value::Bool(x) => {
+            if *x {out.push_str("true")} else {out.push_str("false")}
         }
-        ExprAST::StringLiteral(s) => write_internal(s, fop),
-        _ => panic!("Unsupported expr: {:?}", build_expr_debug_strings(e))
+        Value::Str(s) => out.push_str(s),
+        _ => return Err(format!("Can't write {}", v.summary()))
     }
+    Ok(())
 }
-fn writeline(e: &ExprAST, fop: &mut IroncamelFileInfo) {
-    write(e, fop);
-    write_internal("\n", fop);
+fn writeline(v: &Value, out: &mut String) -> Result<(), String> {
+    write(v, out)?;
+    out.push('\n');
+    Ok(())
 }
 
 enum ArithmeticCalcOp {
-    Add, Minus, Multiple
+    Add, Minus, Multiple, Divide, Modulo
 }
 enum ArithmeticCmpOp {
-    Gt, Lt, Geq, Leq, Eq
+    Gt, Lt, Geq, Leq, Eq, Ne
 }
 
-pub fn call_builtin_function(func_name: &str, params: Vec<ExprAST>) -> ExprAST {
-    debug!("Called builtin {} with params: {:?}", func_name, &params);
-    match func_name {
-        "==" => arithmetic_cmp(ArithmeticCmpOp::Eq, &params),
-        ">" => arithmetic_cmp(ArithmeticCmpOp::Gt, &params),
-        "<" => arithmetic_cmp(ArithmeticCmpOp::Lt, &params),
-        ">=" => arithmetic_cmp(ArithmeticCmpOp::Geq, &params),
-        "<=" => arithmetic_cmp(ArithmeticCmpOp::Leq, &params),
-        "+"  => arithmetic_calc(ArithmeticCalcOp::Add, &params),
-        "-"  => arithmetic_calc(ArithmeticCalcOp::Minus, &params),
-        "*"  => arithmetic_calc(ArithmeticCalcOp::Multiple, &params),
-        "list" => {
-            ExprAST::List(Rc::new(IroncamelLinkedList::build_list(params.as_slice())))
+// A failure (e.g. hd of an empty list) is returned as a message, the interpreter adds the stack trace.
+// The number of params is only checked here for a builtin called through a variable
+pub fn call_builtin_function(func_name: &str, params: &[Value], natives: &Natives) -> Result<Value, String> {
+    debug!("Called builtin {} with params: {:?}", func_name, params);
+    match known_arity(func_name) {
+        Some(Some(arity)) if arity != params.len() => {
+            return Err(format!("{} expects {} arguments, got {}", func_name, arity, params.len()));
+        },
+        Some(_) => (),
+        None => {
+            return natives.call(func_name, params)
+                .unwrap_or_else(|| panic!("Builtin function ({}) not found", func_name));
         }
+    }
+    match func_name {
+        "==" => arithmetic_cmp(ArithmeticCmpOp::Eq, params),
+        "!=" => arithmetic_cmp(ArithmeticCmpOp::Ne, params),
+        ">" => arithmetic_cmp(ArithmeticCmpOp::Gt, params),
+        "<" => arithmetic_cmp(ArithmeticCmpOp::Lt, params),
+        ">=" => arithmetic_cmp(ArithmeticCmpOp::Geq, params),
+        "<=" => arithmetic_cmp(ArithmeticCmpOp::Leq, params),
+        "+"  => arithmetic_calc(ArithmeticCalcOp::Add, params),
+        "-"  => arithmetic_calc(ArithmeticCalcOp::Minus, params),
+        "*"  => arithmetic_calc(ArithmeticCalcOp::Multiple, params),
+        "/"  => arithmetic_calc(ArithmeticCalcOp::Divide, params),
+        "%"  => arithmetic_calc(ArithmeticCalcOp::Modulo, params),
+        "list" => Ok(Value::list(params)),
         "cons" => {
             assert_eq!(params.len(), 2);
             let tail = match &params[1] {
-                ExprAST::List(l) => l,
-                _ => panic!("Expect a list as the second param, got {:?}",&params[1])
+                Value::Thunk(t) => t.value().unwrap_or_else(|| params[1].clone()),
+                v => v.clone()
             };
-            let result = IroncamelLinkedList::cons(params[0].clone(),
-                                                   tail);
-            ExprAST::List(Rc::new(result))
+            match tail {
+                Value::List(l) => Ok(Value::List(Rc::new(IroncamelLinkedList::cons(params[0].clone(), &l)))),
+                Value::Thunk(_) | Value::LazyCons(_) => {
+                    Ok(Value::LazyCons(Rc::new(LazyCons { head: params[0].clone(), tail })))
+                },
+                _ => Err(format!("Expect a list as the second param, got {}", params[1].summary()))
+            }
         },
         "hd" => {
             assert_eq!(params.len(), 1);
-            match &params[0] {
-                ExprAST::List(l) => l.hd().clone(),
-                _ => panic!("Expect a list, got {:?}",&params[0])
+            if let Value::LazyCons(c) = &params[0] {
+                return Ok(c.head.clone());
+            }
+            let list = unpack_list(&params[0])?;
+            if list.len == 0 {
+                return Err(String::from("hd of an empty list"));
             }
+            Ok(list.hd().clone())
         },
         "tl" => {
             assert_eq!(params.len(), 1);
-            match &params[0] {
-                ExprAST::List(l) => {
-                    match l.tl() {
-                        Some(t) => ExprAST::List(t),
-                        None => build_empty_list_expr()
-                    }
-                },
-                _ => panic!("Expect a list, got {:?}",&params[0])
+            if let Value::LazyCons(c) = &params[0] {
+                return Ok(c.tail.clone());
+            }
+            let list = unpack_list(&params[0])?;
+            if list.len == 0 {
+                return Err(String::from("tl of an empty list"));
+            }
+            match list.tl() {
+                Some(t) => Ok(Value::List(t)),
+                None => Ok(Value::empty_list())
             }
         },
         "is_empty" => {
             assert_eq!(params.len(), 1);
-            match &params[0] {
-                ExprAST::List(l) => {
-                    let r = l.len == 0;
-                    ExprAST::Bool(r)
-                },
-                _ => panic!("Expect a list, got {:?}",&params[0])
+            if let Value::LazyCons(_) = &params[0] {
+                return Ok(Value::Bool(false));
             }
+            Ok(Value::Bool(unpack_list(&params[0])?.len == 0))
         },
         "atoi" => {
             assert_eq!(params.len(), 1);
-            match &params[0] {
-                ExprAST::StringLiteral(s) => {
-                    let x:i64 = s.parse().unwrap();
-                    ExprAST::Int(x)
-                },
-                _ => panic!("Expect a String, got {:?}",&params[0])
+            let s = unpack_str(&params[0])?;
+            match (s.parse::<i64>(), s.parse::<BigInt>()) {
+                (Ok(x), _) => Ok(Value::Int(x)),
+                (_, Ok(x)) => Ok(Value::integer(x)),
+                _ => Err(format!("atoi can't parse {}", params[0].summary()))
             }
         },
         "strtok" => {
             assert_eq!(params.len(), 2);
-            let origin_str = match &params[0] {
-                ExprAST::StringLiteral(s) => s,
-                _ => panic!("Expect a String, got {:?}",&params[0])
-            };
-            let delims = match &params[1] {
-                ExprAST::StringLiteral(s) => s,
-                _ => panic!("Expect a String, got {:?}",&params[0])
-            };
+            let origin_str = unpack_str(&params[0])?;
+            let delims = unpack_str(&params[1])?;
             debug!("got delims {}", delims);
             let delim_list: Vec<char> = delims.chars().collect();
             debug!("We have {} delims", delim_list.len());
             let split_str: Vec<&str> = origin_str.split(&delim_list[..]).collect();
             debug!("split: {:?}", split_str);
-            let mut result: Vec<ExprAST> = Vec::new();
+            let mut result: Vec<Value> = Vec::new();
             for s in split_str {
                 if s.is_empty() { continue }
-                let e = ExprAST::StringLiteral(s.to_owned());
-                result.push(e);
+                result.push(Value::str(s));
             }
-            ExprAST::List(
-                Rc::new(IroncamelLinkedList::build_list(&result)
-                ))
-
+            Ok(Value::list(&result))
+        },
+        "assert_eq" => {
+            assert_eq!(params.len(), 2);
+            // The message is what `ironcamel test` reports for a failed test
+            if !values_equal(&params[0], &params[1]) {
+                return Err(format!("assert_eq failed: {} != {}", params[0].summary(), params[1].summary()));
+            }
+            Ok(Value::Bool(true))
+        },
+        "not" => {
+            assert_eq!(params.len(), 1);
+            Ok(Value::Bool(!unpack_bool(&params[0])?))
+        },
+        "abs" | "min" | "max" | "pow" | "wrapping_add" | "wrapping_sub" | "wrapping_mul" | "wrapping_pow" => {
+            integer_function(func_name, params)
         },
+        "concat" | "str_len" | "substr" | "char_at" | "index_of" | "starts_with" | "ends_with" | "trim" | "upper" |
+        "lower" | "replace" | "itoa" | "to_string" | "split" | "join" => string_function(func_name, params),
         _ => panic!("Builtin function ({}) not found", func_name)
     }
 }
 
 
 
-fn arithmetic_calc(op: ArithmeticCalcOp, p: &Vec<ExprAST>) -> ExprAST {
+// Strings are indexed and measured in unicode scalar values (chars), never in bytes
+fn string_function(func_name: &str, p: &[Value]) -> Result<Value, String> {
+    match func_name {
+        "concat" => Ok(Value::str(&format!("{}{}", unpack_str(&p[0])?, unpack_str(&p[1])?))),
+        "str_len" => Ok(Value::Int(unpack_str(&p[0])?.chars().count() as i64)),
+        // substr(s, start, length)
+        "substr" => {
+            let s = unpack_str(&p[0])?;
+            let (start, length) = (unpack_num(&p[1])?, unpack_num(&p[2])?);
+            let count = s.chars().count() as i64;
+            if start < 0 || length < 0 || start > count || length > count - start {
+                return Err(format!("substr({}, {}, {}) is out of range, the string has {} characters",
+                                   p[0].summary(), start, length, count));
+            }
+            Ok(Value::str(&s.chars().skip(start as usize).take(length as usize).collect::<String>()))
+        },
+        "char_at" => {
+            let s = unpack_str(&p[0])?;
+            let index = unpack_num(&p[1])?;
+            match usize::try_from(index).ok().and_then(|i| s.chars().nth(i)) {
+                Some(c) => Ok(Value::str(c.encode_utf8(&mut [0; 4]))),
+                None => Err(format!("char_at({}, {}) is out of range, the string has {} characters",
+                                    p[0].summary(), index, s.chars().count()))
+            }
+        },
+        // -1 if it's not found
+        "index_of" => {
+            let s = unpack_str(&p[0])?;
+            match s.find(unpack_str(&p[1])?) {
+                Some(byte) => Ok(Value::Int(s[..byte].chars().count() as i64)),
+                None => Ok(Value::Int(-1))
+            }
+        },
+        "starts_with" => Ok(Value::Bool(unpack_str(&p[0])?.starts_with(unpack_str(&p[1])?))),
+        "ends_with" => Ok(Value::Bool(unpack_str(&p[0])?.ends_with(unpack_str(&p[1])?))),
+        "trim" => Ok(Value::str(unpack_str(&p[0])?.trim())),
+        "upper" => Ok(Value::str(&unpack_str(&p[0])?.to_uppercase())),
+        "lower" => Ok(Value::str(&unpack_str(&p[0])?.to_lowercase())),
+        // replace(s, from, to) replaces every occurrence
+        "replace" => Ok(Value::str(&unpack_str(&p[0])?.replace(unpack_str(&p[1])?, unpack_str(&p[2])?))),
+        "itoa" => Ok(Value::str(&unpack_integer(&p[0])?.to_string())),
+        // As written by writeline, lists as in error messages
+        "to_string" => match &p[0] {
+            Value::Int(_) | Value::BigInt(_) | Value::Bool(_) | Value::List(_) => Ok(Value::str(&p[0].to_string())),
+            Value::Str(_) => Ok(p[0].clone()),
+            v => Err(format!("Can't convert {} to a string", v.summary()))
+        },
+        // Unlike strtok, the separator is a whole string and empty fields are kept
+        "split" => {
+            let s = unpack_str(&p[0])?;
+            let separator = unpack_str(&p[1])?;
+            if separator.is_empty() {
+                return Err(String::from("split with an empty separator"));
+            }
+            let fields: Vec<Value> = s.split(separator).map(Value::str).collect();
+            Ok(Value::list(&fields))
+        },
+        // join(list, separator)
+        "join" => {
+            let separator = unpack_str(&p[1])?;
+            let strings = unpack_list(&p[0])?.iter().map(unpack_str).collect::<Result<Vec<_>, _>>()?;
+            Ok(Value::str(&strings.join(separator)))
+        },
+        _ => panic!("Not a string builtin: {}", func_name)
+    }
+}
+
+// Integers are promoted to BigInt instead of overflowing, the i64 path stays the fast one.
+// / and % round towards zero like Rust
+fn arithmetic_calc(op: ArithmeticCalcOp, p: &[Value]) -> Result<Value, String> {
     assert_eq!(p.len(), 2);
-    let a = unpack_num(&p[0]);
-    let b = unpack_num(&p[1]);
+    if let (Value::Int(a), Value::Int(b)) = (&p[0], &p[1]) {
+        let result = match op {
+            ArithmeticCalcOp::Add => a.checked_add(*b),
+            ArithmeticCalcOp::Minus => a.checked_sub(*b),
+            ArithmeticCalcOp::Multiple => a.checked_mul(*b),
+            ArithmeticCalcOp::Divide => a.checked_div(*b),
+            ArithmeticCalcOp::Modulo => a.checked_rem(*b),
+        };
+        if let Some(x) = result {
+            return Ok(Value::Int(x));
+        }
+    }
+    let a = unpack_integer(&p[0])?;
+    let b = unpack_integer(&p[1])?;
     let result = match op {
         ArithmeticCalcOp::Add => a + b,
         ArithmeticCalcOp::Minus => a - b,
         ArithmeticCalcOp::Multiple => a * b,
+        ArithmeticCalcOp::Divide if b.is_zero() => return Err(format!("division by zero: /({}, {})", p[0], p[1])),
+        ArithmeticCalcOp::Modulo if b.is_zero() => return Err(format!("division by zero: %({}, {})", p[0], p[1])),
+        ArithmeticCalcOp::Divide => a / b,
+        ArithmeticCalcOp::Modulo => a % b,
     };
-    ExprAST::Int(result)
+    Ok(Value::integer(result))
 }
 
-fn arithmetic_cmp(op: ArithmeticCmpOp, p: &Vec<ExprAST>) -> ExprAST {
-    assert_eq!(p.len(), 2);
-    let a = unpack_num(&p[0]);
-    let b = unpack_num(&p[1]);
-    let result = match op {
-        ArithmeticCmpOp::Eq => a == b,
-        ArithmeticCmpOp::Gt => a > b,
-        ArithmeticCmpOp::Lt => a < b,
-        ArithmeticCmpOp::Geq => a >= b,
-        ArithmeticCmpOp::Leq => a <= b
+// The wrapping_* builtins stay in 64 bits, the others grow like the arithmetic operators
+fn integer_function(func_name: &str, p: &[Value]) -> Result<Value, String> {
+    match (func_name, p) {
+        ("abs", [Value::Int(a)]) if *a != i64::MIN => return Ok(Value::Int(a.abs())),
+        ("min", [Value::Int(a), Value::Int(b)]) => return Ok(Value::Int(*a.min(b))),
+        ("max", [Value::Int(a), Value::Int(b)]) => return Ok(Value::Int(*a.max(b))),
+        ("wrapping_add" | "wrapping_sub" | "wrapping_mul" | "wrapping_pow", _) => {
+            let (a, b) = (unpack_num(&p[0])?, unpack_num(&p[1])?);
+            return Ok(Value::Int(match func_name {
+                "wrapping_add" => a.wrapping_add(b),
+                "wrapping_sub" => a.wrapping_sub(b),
+                "wrapping_mul" => a.wrapping_mul(b),
+                _ => wrapping_pow(a, b)?
+            }));
+        },
+        _ => ()
+    }
+    let params = p.iter().map(unpack_integer).collect::<Result<Vec<_>, _>>()?;
+    let result = match (func_name, &params[..]) {
+        ("abs", [a]) => a.abs(),
+        ("min", [a, b]) => a.min(b).clone(),
+        ("max", [a, b]) => a.max(b).clone(),
+        ("pow", [a, b]) => pow(a, b)?,
+        _ => panic!("Not an integer builtin: {}", func_name)
     };
-    ExprAST::Bool(result)
+    Ok(Value::integer(result))
 }
 
-fn unpack_num(e: &ExprAST) -> i64 {
-    match e {
-        ExprAST::Int(x) => *x,
-        _ => panic!("Expected int, got {:?}", build_expr_debug_strings(e))
+// The exponent is at most u32::MAX, anything above is only computed for 0, 1 and -1.
+// The result has at most MAX_POW_BITS bits, estimated before computing it: |base|^e has at most bits(base) * e
+fn pow(base: &BigInt, exponent: &BigInt) -> Result<BigInt, String> {
+    if exponent.is_negative() {
+        return Err(format!("negative exponent: pow({}, {})", base, exponent));
+    }
+    match u32::try_from(exponent) {
+        Ok(e) if base.magnitude().bits().saturating_mul(u64::from(e)) > MAX_POW_BITS && base.abs() > BigInt::one() =>
+            Err(format!("result too large: pow({}, {}) has more than {} bits", base, exponent, MAX_POW_BITS)),
+        Ok(e) => Ok(base.pow(e)),
+        Err(_) if base.is_zero() || base.is_one() => Ok(base.clone()),
+        Err(_) if *base == BigInt::from(-1) => Ok(if (exponent % 2u32).is_zero() { BigInt::one() } else { base.clone() }),
+        Err(_) => Err(format!("exponent too large: pow({}, {})", base, exponent))
     }
 }
 
-
-pub struct IroncamelLinkedList {
-    value: Box<ExprAST>,
-    pub(crate) len: usize, // Allows us to calculate list size with O(1) cost
-    next: Option<std::rc::Rc<IroncamelLinkedList>>
-}
-
-pub fn build_empty_list_expr() -> ExprAST {
-    ExprAST::List(Rc::new(IroncamelLinkedList::build_empty_list()))
-}
-impl IroncamelLinkedList {
-    pub(crate) fn build_empty_list() -> IroncamelLinkedList{
-        IroncamelLinkedList {
-            value: Box::new(ExprAST::Error),
-            len: 0,
-            next: None
-        }
-    }
-    pub fn build_list(exprs: &[ExprAST]) -> IroncamelLinkedList {
-        if exprs.len() == 0 {
-            IroncamelLinkedList::build_empty_list()
-        } else {
-            if exprs.len() == 1 {
-                IroncamelLinkedList::build(exprs[0].clone())
-            } else {
-                let tail_exprs = &exprs[1..];
-                assert_eq!(exprs.len(), tail_exprs.len()+1);
-                let tail_link = IroncamelLinkedList::build_list(tail_exprs);
-                assert_eq!(tail_link.len, tail_exprs.len());
-                IroncamelLinkedList::cons(exprs[0].clone(), &Rc::new(tail_link))
-            }
-        }
-    }
-    pub fn build(expr: ExprAST) -> IroncamelLinkedList {
-        IroncamelLinkedList {
-            value: Box::new(expr),
-            len: 1,
-            next: None
-        }
+fn wrapping_pow(base: i64, exponent: i64) -> Result<i64, String> {
+    if exponent < 0 {
+        return Err(format!("negative exponent: wrapping_pow({}, {})", base, exponent));
     }
-    pub fn cons(expr: ExprAST, tail: &Rc<IroncamelLinkedList>) -> IroncamelLinkedList {
-        IroncamelLinkedList {
-            value: Box::new(expr),
-            len: 1 + tail.len,
-            next: Some(std::rc::Rc::clone(tail)),
-        }
-    }
-    pub fn hd(&self) -> &ExprAST {
-        assert!(self.len > 0);
-        &self.value
-    }
-    pub fn tl(&self) -> Option<Rc<IroncamelLinkedList>> {
-        assert!(self.len > 0);
-        if self.len > 1 {
-            let tail = self.next.as_ref().unwrap();
-            Some(std::rc::Rc::clone(&tail))
-        } else {
-            None
+    // By squaring, the exponent may not fit the u32 of i64::wrapping_pow
+    let (mut result, mut base, mut exponent) = (1i64, base, exponent);
+    while exponent > 0 {
+        if exponent % 2 == 1 {
+            result = result.wrapping_mul(base);
         }
+        base = base.wrapping_mul(base);
+        exponent /= 2;
     }
+    Ok(result)
+}
 
-    pub fn as_vector(&self) -> Vec<Box<ExprAST>> {
-        if self.len == 1 {
-            vec![self.value.clone()]
-        } else {
-            match &self.next {
-                None => { panic!("Expected to have a tail") }
-                Some(next) => {
-                    assert_eq!(next.len+1, self.len);
-                    let mut result = next.as_vector();
-                    result.push(self.value.clone());
-                    result
-                }
-            }
-        }
-    }
-    pub fn as_vector_i64(&self) -> Vec<i64> {
-        let exprs = self.as_vector();
-        let mut result = Vec::with_capacity(exprs.len());
-        assert_eq!(exprs.len(), self.len);
-        for i in 0..self.len {
-            let v = match *exprs[i] {
-                ExprAST::Int(x) => x,
-                _ => panic!("Expect an integer")
-            };
-            result.push(v);
-        }
-        result.reverse();
-        result
-    }
+// == and != compare any two values structurally, the others order integers, strings and lists
+fn arithmetic_cmp(op: ArithmeticCmpOp, p: &[Value]) -> Result<Value, String> {
+    assert_eq!(p.len(), 2);
+    let ordering = match (&p[0], &p[1], &op) {
+        (Value::Int(a), Value::Int(b), _) => a.cmp(b),
+        (a, b, ArithmeticCmpOp::Eq) => return Ok(Value::Bool(values_equal(a, b))),
+        (a, b, ArithmeticCmpOp::Ne) => return Ok(Value::Bool(!values_equal(a, b))),
+        (a, b, _) => compare_values(a, b)?
+    };
+    let result = match op {
+        ArithmeticCmpOp::Eq => ordering.is_eq(),
+        ArithmeticCmpOp::Ne => ordering.is_ne(),
+        ArithmeticCmpOp::Gt => ordering.is_gt(),
+        ArithmeticCmpOp::Lt => ordering.is_lt(),
+        ArithmeticCmpOp::Geq => ordering.is_ge(),
+        ArithmeticCmpOp::Leq => ordering.is_le()
+    };
+    Ok(Value::Bool(result))
 }
 
-impl Clone for IroncamelLinkedList {
-    fn clone(&self) -> Self {
-        // https://stackoverflow.com/a/61950053/1166518
-        let next = match &self.next {
-            Some(s) => Some(std::rc::Rc::clone(s)),
-            None => None
-        };
-        IroncamelLinkedList {
-            value: self.value.clone(),
-            len: self.len,
-            next
-        }
+fn unpack_num(v: &Value) -> Result<i64, String> {
+    match v {
+        Value::Int(x) => Ok(*x),
+        Value::BigInt(_) => Err(format!("{} doesn't fit in 64 bits", v.summary())),
+        _ => Err(format!("Expected int, got {}", v.summary()))
     }
 }
 
+fn unpack_integer(v: &Value) -> Result<BigInt, String> {
+    v.to_bigint().ok_or_else(|| format!("Expected int, got {}", v.summary()))
+}
 
-#[cfg(test)]
-mod tests {
-    use crate::builtin::IroncamelLinkedList;
-    use crate::expr::ExprAST;
-
-    fn gei(x:i64) -> ExprAST { //generate expr int
-        ExprAST::Int(x)
-    }
-    #[test]
-    fn build_linkedlist() {
-        let list = IroncamelLinkedList::build(gei(5));
-        assert_eq!(list.len, 1);
+fn unpack_bool(v: &Value) -> Result<bool, String> {
+    match v {
+        Value::Bool(x) => Ok(*x),
+        _ => Err(format!("Expect a boolean value, got {}", v.summary()))
     }
+}
 
-    #[test]
-    fn insert_to_list() {
-        let l1 = IroncamelLinkedList::build(gei(5));
-        assert_eq!(l1.as_vector_i64(), vec![5]);
-        let l2 = IroncamelLinkedList::cons(gei(42), &std::rc::Rc::new(l1));
-        assert_eq!(l2.as_vector_i64(), vec![42, 5]);
+fn unpack_list(v: &Value) -> Result<&IroncamelLinkedList, String> {
+    match v {
+        Value::List(l) => Ok(l),
+        _ => Err(format!("Expect a list, got {}", v.summary()))
     }
+}
 
-    #[test]
-    fn car_cdr_list() {
-        let l1 = IroncamelLinkedList::build(gei(5));
-        assert_eq!(l1.as_vector_i64(), vec![5]);
-        let l2 = IroncamelLinkedList::cons(gei(42), &std::rc::Rc::new(l1));
-        assert_eq!(l2.as_vector_i64(), vec![42, 5]);
-
-        let v = l2.hd();
-        match v {
-            ExprAST::Int(x) => assert_eq!(*x, 42),
-            _ => assert!(false)
-        };
-
-        let l3 = l2.tl();
-        match l3 {
-            Some(l3) => assert_eq!(l3.as_vector_i64(), vec![5]),
-            None => assert!(false)
-        };
-
+fn unpack_str(v: &Value) -> Result<&str, String> {
+    match v {
+        Value::Str(s) => Ok(s),
+        _ => Err(format!("Expect a String, got {}", v.summary()))
     }
 }
-
@@ -0,0 +1,346 @@
+// A compact bytecode for the virtual machine (see vm.rs), compiled from the lowered program.
+// Names are already resolved by lower.rs, this pass only flattens the code:
+//   - Every function or closure runs in a frame of `locals` slots on the VM's value stack, the parameters first.
+//     A nested block gets slots of its own in the frame of its function, so entering it costs nothing.
+//   - A closure copies the values it uses from the enclosing function into its upvalues when it's created.
+//     Nothing is mutable, so there is no need to share them.
+//   - Expressions push their value on top of the frame, a call finds its arguments there.
+//   - A call in tail position (see the evaluator in interpreter.rs) reuses the frame of its caller.
+//
+// FunctionIds and ClosureIds are the same as in the lowered program, so the VM shares Value with the interpreter.
+// So are SiteIds, the calls have the same locations in stack traces.
+// A closure is Value::Closure with its upvalues in a single frame Env.
+
+use crate::lower::{BlockCode, Callee, ClosureId, Code, FunctionCode, FunctionId, LoweredProgram, SiteId, StatementCode};
+use crate::parser::FileOpenAst;
+use crate::value::Value;
+
+#[derive(Debug)]
+pub enum Op {
+    // Push program.constants[i]
+    Const(usize),
+    Local(usize),
+    // Push upvalues[i] of the running closure
+    Upvalue(usize),
+    // A global function or a builtin used as a value
+    Function(FunctionId),
+    Builtin(&'static str),
+    // Capture the upvalues listed in the closure's `captures`
+    Closure(ClosureId),
+    // Pop a let binding, or a value read by IO, into a local slot
+    SetLocal(usize),
+    // Pop the condition of an if, it has to be a boolean
+    JumpIfFalse(usize),
+    Jump(usize),
+    // Below the arguments of Call and TailCall, fails early if it can't be called
+    CheckCallable,
+    // The calls take the number of arguments, and end with the site of the call
+    Call(usize, SiteId),
+    CallFunction(FunctionId, usize, SiteId),
+    CallBuiltin(&'static str, usize, SiteId),
+    TailCall(usize, SiteId),
+    TailCallFunction(FunctionId, usize, SiteId),
+    Return,
+    // program.io[i], only in main and tests
+    Io(usize),
+}
+
+// Where a closure finds an upvalue when it's created
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+    // A slot in the frame of the enclosing function
+    Local(usize),
+    // An upvalue of the enclosing closure
+    Upvalue(usize),
+}
+
+pub enum IoOp {
+    // Pushes the value read
+    Read { procedure: String, file_handler: String, variable: String },
+    // Pops the value written
+    Write { procedure: String, file_handler: String },
+    FileOpen(FileOpenAst),
+}
+
+// A function, closure, main or a test
+pub struct Proto {
+    pub name: String,
+    pub location: String,
+    pub arity: usize,
+    // Parameters included
+    pub locals: usize,
+    pub captures: Vec<Capture>,
+    pub code: Vec<Op>,
+}
+
+pub struct BytecodeProgram {
+    pub functions: Vec<Proto>,
+    pub closures: Vec<Proto>,
+    pub main: Option<Proto>,
+    pub tests: Vec<Proto>,
+    pub constants: Vec<Value>,
+    pub io: Vec<IoOp>,
+    pub sites: Vec<String>,
+}
+
+// A frame of the lowered program, mapped to the slots of a VM frame
+struct Scope {
+    // Index into Compiler.functions
+    function: usize,
+    base: usize,
+    bound: usize,
+}
+
+// The function being compiled, and the ones around it
+struct FunctionState {
+    code: Vec<Op>,
+    locals: usize,
+    captures: Vec<Capture>,
+    // Tail calls are not compiled in main and tests, they stay at the bottom of a stack trace
+    tail_calls: bool,
+}
+
+struct Compiler<'p> {
+    program: &'p LoweredProgram,
+    closures: Vec<Option<Proto>>,
+    constants: Vec<Value>,
+    io: Vec<IoOp>,
+    functions: Vec<FunctionState>,
+    scopes: Vec<Scope>,
+}
+
+// The lowered program must be strict, thunks only exist for the interpreter
+pub fn compile_program(program: &LoweredProgram) -> BytecodeProgram {
+    assert!(program.thunks.is_empty(), "The virtual machine doesn't evaluate lazily");
+    let mut compiler = Compiler {
+        program,
+        closures: program.closures.iter().map(|_| None).collect(),
+        constants: Vec::new(),
+        io: Vec::new(),
+        functions: Vec::new(),
+        scopes: Vec::new(),
+    };
+    let functions = program.functions.iter().map(|f| compiler.compile_function(f, true)).collect();
+    let main = program.main.as_ref().map(|m| compiler.compile_function(m, false));
+    let tests = program.tests.iter().map(|t| compiler.compile_function(t, false)).collect();
+    BytecodeProgram {
+        functions,
+        // Every closure expression is compiled where it's defined
+        closures: compiler.closures.into_iter().map(|c| c.unwrap()).collect(),
+        main,
+        tests,
+        constants: compiler.constants,
+        io: compiler.io,
+        sites: program.sites.clone(),
+    }
+}
+
+// Slots taken by the statements of a block
+fn bindings(statements: &[StatementCode]) -> usize {
+    statements.iter().filter(|s| matches!(s, StatementCode::Let(_) | StatementCode::Read { .. })).count()
+}
+
+impl<'p> Compiler<'p> {
+    fn compile_function(&mut self, fun: &'p FunctionCode, tail_calls: bool) -> Proto {
+        let level = self.functions.len();
+        let locals = fun.arity + bindings(&fun.body.statements);
+        self.functions.push(FunctionState { code: Vec::new(), locals, captures: Vec::new(), tail_calls });
+        self.scopes.push(Scope { function: level, base: 0, bound: fun.arity });
+        self.compile_block(&fun.body, true);
+        self.emit(Op::Return);
+        self.scopes.pop();
+        let state = self.functions.pop().unwrap();
+        Proto {
+            name: fun.name.to_owned(),
+            location: fun.location.to_owned(),
+            arity: fun.arity,
+            locals: state.locals,
+            captures: state.captures,
+            code: state.code
+        }
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) {
+        self.current().code.push(op);
+    }
+
+    fn next_address(&mut self) -> usize {
+        self.current().code.len()
+    }
+
+    // Fill in the target of a jump emitted before
+    fn patch(&mut self, at: usize) {
+        let target = self.next_address();
+        match &mut self.current().code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) => *t = target,
+            op => panic!("Not a jump {:?}", op)
+        }
+    }
+
+    // Like lower_nested_block, only a block with statements has a frame in the lowered program
+    fn compile_nested_block(&mut self, block: &'p BlockCode, tail: bool) {
+        if block.statements.is_empty() {
+            return self.compile_code(&block.return_code, tail);
+        }
+        let level = self.functions.len() - 1;
+        let base = self.current().locals;
+        self.current().locals += bindings(&block.statements);
+        self.scopes.push(Scope { function: level, base, bound: 0 });
+        self.compile_block(block, tail);
+        self.scopes.pop();
+    }
+
+    // The next slot of the innermost scope
+    fn bind(&mut self) -> usize {
+        let scope = self.scopes.last_mut().unwrap();
+        scope.bound += 1;
+        scope.base + scope.bound - 1
+    }
+
+    fn compile_block(&mut self, block: &'p BlockCode, tail: bool) {
+        for s in &block.statements {
+            match s {
+                StatementCode::Let(code) => {
+                    self.compile_code(code, false);
+                    let slot = self.bind();
+                    self.emit(Op::SetLocal(slot));
+                },
+                StatementCode::Read { procedure, file_handler, variable } => {
+                    self.io.push(IoOp::Read {
+                        procedure: procedure.to_owned(),
+                        file_handler: file_handler.to_owned(),
+                        variable: variable.to_owned()
+                    });
+                    self.emit(Op::Io(self.io.len() - 1));
+                    let slot = self.bind();
+                    self.emit(Op::SetLocal(slot));
+                },
+                StatementCode::Write { procedure, file_handler, code } => {
+                    self.compile_code(code, false);
+                    self.io.push(IoOp::Write { procedure: procedure.to_owned(), file_handler: file_handler.to_owned() });
+                    self.emit(Op::Io(self.io.len() - 1));
+                },
+                StatementCode::FileOpen(fo) => {
+                    self.io.push(IoOp::FileOpen(fo.clone()));
+                    self.emit(Op::Io(self.io.len() - 1));
+                }
+            }
+        }
+        self.compile_code(&block.return_code, tail);
+    }
+
+    // `tail` if the value is returned right away
+    fn compile_code(&mut self, code: &'p Code, tail: bool) {
+        let tail = tail && self.current().tail_calls;
+        match code {
+            Code::Const(v) => {
+                self.constants.push(v.clone());
+                self.emit(Op::Const(self.constants.len() - 1));
+            },
+            Code::Local { depth, slot } => self.compile_local(*depth, *slot),
+            Code::Function(id) => self.emit(Op::Function(*id)),
+            Code::Builtin(name) => self.emit(Op::Builtin(name)),
+            Code::Call(callee, params, site) => {
+                if let Callee::Local { depth, slot } = callee {
+                    self.compile_local(*depth, *slot);
+                    self.emit(Op::CheckCallable);
+                }
+                for p in params {
+                    self.compile_code(p, false);
+                }
+                let (argc, site) = (params.len(), *site);
+                self.emit(match (callee, tail) {
+                    (Callee::Function(id), false) => Op::CallFunction(*id, argc, site),
+                    (Callee::Function(id), true) => Op::TailCallFunction(*id, argc, site),
+                    (Callee::Builtin(name), _) => Op::CallBuiltin(name, argc, site),
+                    (Callee::Local { .. }, false) => Op::Call(argc, site),
+                    (Callee::Local { .. }, true) => Op::TailCall(argc, site),
+                });
+            },
+            Code::If(condition, then_case, else_case) => {
+                self.compile_code(condition, false);
+                let to_else = self.next_address();
+                self.emit(Op::JumpIfFalse(0));
+                self.compile_nested_block(then_case, tail);
+                if tail {
+                    self.emit(Op::Return);
+                    self.patch(to_else);
+                    self.compile_nested_block(else_case, tail);
+                } else {
+                    let to_end = self.next_address();
+                    self.emit(Op::Jump(0));
+                    self.patch(to_else);
+                    self.compile_nested_block(else_case, tail);
+                    self.patch(to_end);
+                }
+            },
+            Code::Block(block) => self.compile_nested_block(block, tail),
+            Code::Closure(id) => {
+                let program = self.program;
+                let proto = self.compile_function(&program.closures[*id], true);
+                self.closures[*id] = Some(proto);
+                self.emit(Op::Closure(*id));
+            },
+            Code::Delay(_) => panic!("The virtual machine doesn't evaluate lazily")
+        }
+    }
+
+    fn compile_local(&mut self, depth: usize, slot: usize) {
+        let scope = &self.scopes[self.scopes.len() - 1 - depth];
+        let (owner, slot) = (scope.function, scope.base + slot);
+        let level = self.functions.len() - 1;
+        if owner == level {
+            self.emit(Op::Local(slot));
+        } else {
+            let index = self.capture(level, owner, slot);
+            self.emit(Op::Upvalue(index));
+        }
+    }
+
+    // The upvalue of the function at `level` holding a slot of the function at `owner`, added if needed
+    fn capture(&mut self, level: usize, owner: usize, slot: usize) -> usize {
+        let source = if owner == level - 1 {
+            Capture::Local(slot)
+        } else {
+            Capture::Upvalue(self.capture(level - 1, owner, slot))
+        };
+        let captures = &mut self.functions[level].captures;
+        match captures.iter().position(|c| *c == source) {
+            Some(index) => index,
+            None => {
+                captures.push(source);
+                captures.len() - 1
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{Capture, compile_program, Op};
+    use crate::lower::lower_program;
+    use crate::parser::build_ast;
+    use crate::tokenizer::convert_source_to_tokens;
+
+    #[test]
+    fn upvalues_and_tail_calls() {
+        let code = "fn f(a, b) { let c = +(a, b); let g = |x| { let h = |y| { +(y, c) }; h(x) }; if true then { g(1) } else { f(b, a) } }";
+        let program = compile_program(&lower_program(&build_ast(&convert_source_to_tokens(code))));
+        let f = &program.functions[0];
+        assert_eq!(f.locals, 4);
+        assert!(matches!(f.code[..], [Op::Local(0), Op::Local(1), Op::CallBuiltin("+", 2, _), Op::SetLocal(2), Op::Closure(1), Op::SetLocal(3), ..]));
+        assert!(f.code.iter().any(|op| matches!(op, Op::TailCall(1, _))));
+        assert!(f.code.iter().any(|op| matches!(op, Op::TailCallFunction(0, 2, _))));
+
+        // h reaches c of f through g
+        let (g, h) = (&program.closures[1], &program.closures[0]);
+        assert_eq!(g.captures, vec![Capture::Local(2)]);
+        assert_eq!(h.captures, vec![Capture::Upvalue(0)]);
+        assert!(matches!(h.code[..], [Op::Local(0), Op::Upvalue(0), Op::CallBuiltin("+", 2, _), Op::Return]));
+    }
+}
@@ -1,6 +1,5 @@
 use std::fmt;
 use crate::expr::{ExprAST, IfElseExpr};
-use crate::interpreter::CallableObject;
 use crate::parser::{StatementAST, LetBindingAST, AST, DEBUG_TREE_INDENT, FunctionAST, BlockAST, ReadAst, ProgramAST, WriteAst};
 
 pub fn build_statement_debug_strings(statement: &StatementAST) -> Vec<String> {
@@ -65,10 +64,11 @@ pub fn build_expr_debug_strings(expr: &ExprAST) -> Vec<String> {
     return match expr {
         ExprAST::If(s) => s.debug_strings(),
         ExprAST::Int(i) => vec![  format!("Integer: {val}", val=i) ],
+        ExprAST::BigInt(i) => vec![  format!("Integer: {val}", val=i) ],
         ExprAST::Bool(b) => vec![ format!("Bool: {val}", val=if *b {"true"} else {"false"}) ],
         ExprAST::Variable(v)  => vec![  format!("Variable: {val}", val=v) ],
         ExprAST::StringLiteral(v) => vec![  format!("Str: {val}", val=v) ],
-        ExprAST::CallCallableObjectByname(func_name, args) => {
+        ExprAST::CallCallableObjectByname(func_name, args, _) => {
             let mut debug = Vec::with_capacity(1 + args.len());
             debug.push( format!("Call: {val}", val=func_name) );
             for expr in args {
@@ -78,19 +78,8 @@ pub fn build_expr_debug_strings(expr: &ExprAST) -> Vec<String> {
             debug
         },
         ExprAST::Block(block) => block.debug_strings(),
-        ExprAST::CallBuiltinFunction(func_name, args) => {
-            let mut debug = Vec::with_capacity(1 + args.len());
-            debug.push( format!("CallBuiltin: {val}", val=func_name) );
-            for expr in args {
-                let single_line = build_expr_debug_strings(expr).join(" ");
-                debug.push(DEBUG_TREE_INDENT.to_owned() + &single_line);
-            }
-            debug
-        }
         ExprAST::Error => vec![String::from("ERROR EXPR")],
 
-        ExprAST::List(_) => vec![String::from("LinkedList")],
-        ExprAST::Callable(co) => vec![build_callable_object_debug_string(co)],
         ExprAST::Closure(clos) => {
             let mut debug = Vec::with_capacity(1 + clos.params.len());
             debug.push(format!("Closure: {val}", val = clos.params.join(" ,")));
@@ -103,18 +92,6 @@ pub fn build_expr_debug_strings(expr: &ExprAST) -> Vec<String> {
     };
 }
 
-pub fn build_callable_object_debug_string(co: &CallableObject) -> String {
-    match co {
-        CallableObject::GlobalFunction(g) => { format!("Global function [{v}]", v=g)}
-        CallableObject::BuiltinFunction(b) => { format!("Builtin function [{v}]", v=b)}
-        CallableObject::Closure(clos, local_env) => {
-            format!("BindedClosure [{c}] in [{e}]",
-                    c=build_expr_debug_strings(&ExprAST::Closure(clos.clone())).join(", "),
-                    e=local_env.keys().cloned().collect::<Vec<String>>().join(","))
-        }
-    }
-}
-
 impl AST for LetBindingAST {
     fn debug_strings(&self) -> Vec<String> {
         let mut debug = Vec::new();
@@ -129,16 +106,16 @@ impl AST for LetBindingAST {
 
 impl AST for FunctionAST {
     fn debug_strings(&self) -> Vec<String> {
-        let mut debug = Vec::with_capacity(1 + self.statements.len());
+        let mut debug = Vec::with_capacity(1 + self.body.statements.len());
         debug.push(format!("Function: {fname} Args: {args}",
                            fname=&self.function_name, args=self.arguments.join(",")));
-        for statement in &self.statements {
+        for statement in &self.body.statements {
             for debug_str in build_statement_debug_strings(statement) {
                 let s:String = DEBUG_TREE_INDENT.to_owned() + &debug_str;
                 debug.push(s);
             }
         }
-        for debug_str in build_expr_debug_strings(&self.return_expr) {
+        for debug_str in build_expr_debug_strings(&self.body.return_expr) {
             let s:String = DEBUG_TREE_INDENT.to_owned() + &debug_str;
             debug.push(s);
         }
@@ -178,6 +155,13 @@ impl AST for ProgramAST {
                 debug.push(s);
             }
         }
+        for t in &self.tests {
+            debug.push(format!("{ind}Test: {name}", ind=DEBUG_TREE_INDENT, name=&t.name));
+            for dbgs in t.block.debug_strings() {
+                let s:String = DEBUG_TREE_INDENT.to_owned() + DEBUG_TREE_INDENT + &dbgs;
+                debug.push(s);
+            }
+        }
         debug
     }
 }
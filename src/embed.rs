@@ -0,0 +1,227 @@
+// IronCamel as a library for Rust hosts: load sources, call their global functions with Rust values,
+// convert what they return and add builtins written in Rust.
+//
+//     let mut interpreter = Interpreter::new(EvalOptions::default());
+//     interpreter.load_file("include/core.icml")?;
+//     interpreter.register("square", Some(1), |args| match args[0] { ... })?;
+//     interpreter.load_source("host", "fn squares(l) { apply(square, l) }")?;
+//     let squares: Vec<i64> = interpreter.call("squares", vec![Value::from(vec![1, 2, 3])])?.try_into()?;
+//
+// Every source is checked together with those loaded before it, so a native has to be registered before
+// a source uses it. The sources are libraries, their functions are never reported as unreachable.
+
+use std::fmt;
+use crate::bytecode::{compile_program, BytecodeProgram};
+use crate::interpreter::{build_global_state, call_function, execute_main_function, main_arguments, EvalOptions, GlobalState,
+                         RuntimeError};
+use crate::io::{FileSystem, IoBackend};
+use crate::lint::LintWarning;
+use crate::lower::{lower_program_with_natives, LoweredProgram};
+use crate::native::Natives;
+use crate::pipeline::{PipelineOptions, SourceFile};
+use crate::resolve::ResolveError;
+use crate::value::{ConversionError, Value};
+
+pub struct Interpreter {
+    options: EvalOptions,
+    natives: Natives,
+    sources: Vec<SourceFile>,
+    warnings: Vec<LintWarning>,
+    program: LoweredProgram,
+    // Only for EvalOptions.vm
+    bytecode: Option<BytecodeProgram>,
+    // Only main does IO
+    io: Box<dyn IoBackend>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io { path: String, error: std::io::Error },
+    // The source is not loaded
+    Check(Vec<ResolveError>),
+    Runtime(RuntimeError),
+    Conversion(ConversionError),
+    // Such as calling an unknown function, or registering a native named like a builtin
+    Usage(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "Can't read {}: {}", path, error),
+            Error::Check(errors) => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}", errors.join("\n"))
+            },
+            Error::Runtime(e) => write!(f, "{}", e),
+            Error::Conversion(e) => write!(f, "{}", e),
+            Error::Usage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RuntimeError> for Error {
+    fn from(e: RuntimeError) -> Error {
+        Error::Runtime(e)
+    }
+}
+
+impl From<ConversionError> for Error {
+    fn from(e: ConversionError) -> Error {
+        Error::Conversion(e)
+    }
+}
+
+impl Interpreter {
+    pub fn new(options: EvalOptions) -> Interpreter {
+        assert!(!(options.lazy && options.vm), "The virtual machine doesn't evaluate lazily");
+        let natives = Natives::default();
+        let program = lower_program_with_natives(&crate::parser::ProgramAST { functions: Vec::new(), tests: Vec::new() },
+                                                 &natives, options.lazy);
+        Interpreter { options, natives, sources: Vec::new(), warnings: Vec::new(), program, bytecode: None,
+                      io: Box::new(FileSystem::new()) }
+    }
+
+    // `path` is where the code comes from, as shown in stack traces
+    pub fn load_source(&mut self, path: &str, code: &str) -> Result<(), Error> {
+        self.sources.push(SourceFile { path: path.to_owned(), code: code.to_owned(), is_library: true });
+        self.rebuild().inspect_err(|_| { self.sources.pop(); })
+    }
+
+    pub fn load_file(&mut self, path: &str) -> Result<(), Error> {
+        let code = std::fs::read_to_string(path)
+            .map_err(|error| Error::Io { path: path.to_owned(), error })?;
+        self.load_source(path, &code)
+    }
+
+    // A builtin written in Rust, `arity` is None if it takes a variable number of arguments.
+    // An Err returned by the function stops the program like any other runtime error
+    pub fn register(&mut self, name: &str, arity: Option<usize>,
+                    function: impl Fn(&[Value]) -> Result<Value, String> + 'static) -> Result<(), Error> {
+        let previous = self.natives.clone();
+        self.natives.register(name, arity, function).map_err(Error::Usage)?;
+        self.rebuild().inspect_err(|_| self.natives = previous)
+    }
+
+    // Where main reads and writes, the stdin and stdout of the process and the real files by default
+    pub fn set_io(&mut self, io: impl IoBackend + 'static) {
+        self.io = Box::new(io);
+    }
+
+    // Found in the sources loaded so far
+    pub fn warnings(&self) -> &[LintWarning] {
+        &self.warnings
+    }
+
+    // Every call starts with fresh limits and usage
+    pub fn call(&self, function: &str, arguments: Vec<Value>) -> Result<Value, Error> {
+        let id = *self.program.function_ids.get(function)
+            .ok_or_else(|| Error::Usage(format!("no function `{}`", function)))?;
+        let arity = self.program.functions[id].arity;
+        if arity != arguments.len() {
+            return Err(Error::Usage(format!("{} expects {} arguments, got {}", function, arity, arguments.len())));
+        }
+        let mut global = self.global_state(Box::new(FileSystem::new()));
+        let result = match &self.bytecode {
+            Some(bytecode) => crate::vm::execute_entry(&mut global, bytecode, &bytecode.functions[id], function, arguments),
+            None => call_function(&self.program, &global, id, arguments)
+        };
+        Ok(result?)
+    }
+
+    // Like `ironcamel --run`, with the IoBackend given to set_io and EvalOptions.args for `fn main(args)`
+    pub fn run_main(&mut self) -> Result<Value, Error> {
+        let main = self.program.main.as_ref()
+            .ok_or_else(|| Error::Usage(String::from("no function `main`")))?;
+        let io = std::mem::replace(&mut self.io, Box::new(FileSystem::new()));
+        let mut global = self.global_state(io);
+        let arguments = main_arguments(main.arity, &self.options.args);
+        let result = match &self.bytecode {
+            Some(bytecode) => crate::vm::execute_entry(&mut global, bytecode, bytecode.main.as_ref().unwrap(), "main", arguments),
+            None => execute_main_function(&self.program, &mut global, main, "main", arguments)
+        };
+        self.io = global.io;
+        Ok(result?)
+    }
+
+    fn global_state(&self, io: Box<dyn IoBackend>) -> GlobalState {
+        build_global_state(io, self.options.limits.clone(), self.natives.clone(), self.options.trace.clone())
+    }
+
+    // Check and lower every source again, nothing is replaced if they are rejected
+    fn rebuild(&mut self) -> Result<(), Error> {
+        let options = PipelineOptions { natives: self.natives.clone(), ..PipelineOptions::default() };
+        let compiled = crate::pipeline::build_program(&self.sources, &options).map_err(Error::Check)?;
+        let program = lower_program_with_natives(&compiled.ast, &self.natives, self.options.lazy);
+        self.bytecode = self.options.vm.then(|| compile_program(&program));
+        self.program = program;
+        self.warnings = compiled.warnings;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embed::{Error, Interpreter};
+    use crate::interpreter::EvalOptions;
+    use crate::io::MemoryIo;
+    use crate::value::Value;
+
+    fn interpreter(options: EvalOptions) -> Interpreter {
+        let mut interpreter = Interpreter::new(options);
+        interpreter.load_file("include/core.icml").unwrap();
+        interpreter.register("square", Some(1), |args| match args[0] {
+            Value::Int(x) => Ok(Value::Int(x * x)),
+            _ => Err(format!("square of {}", args[0].summary()))
+        }).unwrap();
+        interpreter.load_source("host", "fn squares(l) { apply(square, l) }\nfn first_square(l) { square(hd(l)) }").unwrap();
+        interpreter
+    }
+
+    #[test]
+    fn call_with_rust_values() {
+        let options = [EvalOptions::default(), EvalOptions { vm: true, ..EvalOptions::default() },
+                       EvalOptions { lazy: true, ..EvalOptions::default() }];
+        for options in options {
+            let interpreter = interpreter(options);
+            let squares: Vec<i64> = interpreter.call("squares", vec![Value::from(vec![1, 2, 3])]).unwrap().try_into().unwrap();
+            assert_eq!(squares, vec![1, 4, 9]);
+            let reversed: Vec<String> = interpreter.call("reverse", vec![Value::from(vec!["a", "b"])]).unwrap().try_into().unwrap();
+            assert_eq!(reversed, vec!["b", "a"]);
+        }
+    }
+
+    #[test]
+    fn run_main_in_memory() {
+        let mut interpreter = interpreter(EvalOptions { vm: true, ..EvalOptions::default() });
+        interpreter.load_source("main", "fn main() { readstr@stdin >> s; writelist@stdout << squares(list(atoi(s), 3)); 0 }").unwrap();
+        let io = MemoryIo::new().with_stdin("2\n");
+        interpreter.set_io(io.clone());
+        assert!(matches!(interpreter.run_main(), Ok(Value::Int(0))));
+        interpreter.run_main().unwrap();
+        assert_eq!(io.stdout(), "4 9 \n4 9 \n");
+    }
+
+    #[test]
+    fn errors() {
+        let mut interpreter = interpreter(EvalOptions::default());
+        assert!(matches!(interpreter.call("nothing", vec![]), Err(Error::Usage(_))));
+        assert!(matches!(interpreter.call("squares", vec![]), Err(Error::Usage(_))));
+        assert!(matches!(interpreter.register("hd", Some(1), |_| Ok(Value::Bool(true))), Err(Error::Usage(_))));
+        assert!(matches!(interpreter.register("if_then", Some(1), |_| Ok(Value::Bool(true))), Err(Error::Usage(_))));
+
+        // A native failing is a runtime error, with the function called at the bottom of the trace
+        match interpreter.call("first_square", vec![Value::from(vec!["x"])]) {
+            Err(Error::Runtime(e)) => assert_eq!(e.to_string(), "square of \"x\"\n    at square(\"x\") (host:2)\n    at first_square (host:2)"),
+            _ => panic!("Expect a runtime error")
+        }
+        assert!(matches!(Vec::<i64>::try_from(Value::from(vec![true])), Err(e) if e.to_string() == "Expect an integer, got true"));
+
+        // A rejected source is not loaded
+        assert!(matches!(interpreter.load_source("bad", "fn f() { g() }"), Err(Error::Check(_))));
+        assert!(matches!(interpreter.call("f", vec![]), Err(Error::Usage(_))));
+        assert!(interpreter.load_source("good", "fn g() { 1 }").is_ok());
+    }
+}
@@ -0,0 +1,106 @@
+// Local environments of the interpreter.
+// Every function or closure body, and every nested block with let bindings, runs in its own frame.
+// A frame is a vector of values in binding order, chained to the frame of the enclosing block,
+// so a variable resolved by the lowering pass to (depth, slot) is found by indexing.
+//
+// Entering a block or capturing a closure only shares the frame. Binding into a frame a closure captured
+// copies that one frame first: the closure keeps seeing what was bound before it, and no Rc cycle appears.
+
+use std::rc::Rc;
+use crate::value::Value;
+
+#[derive(Clone)]
+pub struct Env {
+    frame: Rc<Frame>
+}
+
+#[derive(Clone)]
+struct Frame {
+    values: Vec<Value>,
+    parent: Option<Env>
+}
+
+impl Env {
+    pub fn new(parent: Option<Env>, capacity: usize) -> Env {
+        Env { frame: Rc::new(Frame { values: Vec::with_capacity(capacity), parent }) }
+    }
+
+    // The next slot of the innermost frame
+    pub fn push(&mut self, value: Value) {
+        Rc::make_mut(&mut self.frame).values.push(value);
+    }
+
+    pub fn get(&self, depth: usize, slot: usize) -> &Value {
+        let mut env = self;
+        for _ in 0..depth {
+            env = match &env.frame.parent {
+                Some(p) => p,
+                None => panic!("No frame at depth {}", depth)
+            };
+        }
+        match env.frame.values.get(slot) {
+            Some(v) => v,
+            None => panic!("Slot {} at depth {} is not bound yet", slot, depth)
+        }
+    }
+
+    // Number of values in each frame, innermost first
+    pub fn frame_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![self.frame.values.len()];
+        let mut parent = &self.frame.parent;
+        while let Some(p) = parent {
+            sizes.push(p.frame.values.len());
+            parent = &p.frame.parent;
+        }
+        sizes
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env::new(None, 0)
+    }
+}
+
+// Closures created in a deep recursion may build a long chain, don't drop it recursively
+impl Drop for Frame {
+    fn drop(&mut self) {
+        let mut next = self.parent.take();
+        while let Some(env) = next {
+            next = match Rc::try_unwrap(env.frame) {
+                Ok(mut f) => f.parent.take(),
+                Err(_) => None // Still shared
+            };
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::value::Value;
+
+    fn int_at(env: &Env, depth: usize, slot: usize) -> i64 {
+        match env.get(depth, slot) {
+            Value::Int(x) => *x,
+            _ => panic!("Expect an int")
+        }
+    }
+
+    #[test]
+    fn captured_frame_is_copied_on_push() {
+        let mut outer = Env::new(None, 2);
+        outer.push(Value::Int(1));
+        let captured = outer.clone();
+        outer.push(Value::Int(2));
+        assert_eq!(captured.frame_sizes(), vec![1]);
+        assert_eq!(outer.frame_sizes(), vec![2]);
+
+        let mut inner = Env::new(Some(outer), 1);
+        inner.push(Value::Int(3));
+        assert_eq!(int_at(&inner, 0, 0), 3);
+        assert_eq!(int_at(&inner, 1, 1), 2);
+        assert_eq!(inner.frame_sizes(), vec![1, 2]);
+    }
+}
@@ -3,10 +3,9 @@
 
 use std::fmt::{Debug, Formatter};
 use std::rc::Rc;
+use num_bigint::BigInt;
 use log::{error, warn,debug};
-use crate::builtin::IroncamelLinkedList;
 use crate::debug_output::build_expr_debug_strings;
-use crate::interpreter::CallableObject;
 use crate::parser::{BlockAST, read_block, read_argument_list};
 use crate::tokenizer::Token;
 use crate::tokenizer::Token::{Integer64, LiteralTrue, LiteralFalse, KeywordIf, KeywordThen, KeywordElse, LeftParentheses, RightParentheses};
@@ -14,21 +13,19 @@ use crate::tokenizer::Token::{Integer64, LiteralTrue, LiteralFalse, KeywordIf, K
 #[derive(Clone)]
 pub enum ExprAST {
     Int(i64),
+    // Only a literal too large for Int
+    BigInt(BigInt),
     Bool(bool),
     StringLiteral(String),
     Variable(String),
-    Block(BlockAST),
+    Block(Rc<BlockAST>),
     If(IfElseExpr),
     Closure(Rc<ClosureAST>),
 
-    CallCallableObjectByname(String, Vec<Box<ExprAST>>),
+    // The last is the line of the callee's name for stack traces, 0 if unknown.
+    // The parser puts the position of its token there, build_ast_with_lines turns it into the line
+    CallCallableObjectByname(String, Vec<Box<ExprAST>>, usize),
     Error,
-
-
-    // Below in involved by interpreter
-    CallBuiltinFunction(String, Vec<Box<ExprAST>>),
-    Callable(CallableObject),
-    List(Rc<IroncamelLinkedList>),
 }
 
 impl Debug for ExprAST {
@@ -37,6 +34,13 @@ impl Debug for ExprAST {
     }
 }
 
+// Evaluating a call may raise a runtime error, these never do.
+// So the passes over the AST may drop them without changing what the program does
+pub fn cannot_fail(expr: &ExprAST) -> bool {
+    matches!(expr, ExprAST::Int(_) | ExprAST::BigInt(_) | ExprAST::Bool(_) | ExprAST::StringLiteral(_) | ExprAST::Variable(_)
+        | ExprAST::Closure(_))
+}
+
 
 /* In other parts in my parsr, I would use Option to wrap the AST object
     However, I just found that I can't warp a dyn trait: https://users.rust-lang.org/t/why-doesnt-option-support-dyn-trait/45353/11
@@ -50,6 +54,9 @@ pub fn try_read_expr(tokens: &Vec<Token>, pos: usize) -> (ExprAST, Option<usize>
         Integer64(x) => {
             return (ExprAST::Int(*x), Some(1));
         },
+        Token::BigInteger(x) => {
+            return (ExprAST::BigInt(x.clone()), Some(1));
+        },
         Token::LiteralString(s) => {
             return (ExprAST::StringLiteral(s.to_owned()), Some(1))
         },
@@ -63,11 +70,17 @@ pub fn try_read_expr(tokens: &Vec<Token>, pos: usize) -> (ExprAST, Option<usize>
             let (ast, len) = read_if_expr(tokens, pos);
             return (ExprAST::If(ast), Some(len));
         }
+        // `||` is also the head of a closure without parameters
+        Token::IdentifierToken(s) if s == "||" && tokens[pos+1] != LeftParentheses => {
+            let (block, len) = read_block(tokens, pos+1);
+            let ast = ClosureAST{ params: Vec::new(), block };
+            return (ExprAST::Closure(Rc::new(ast)), Some(len + 1));
+        }
         Token::IdentifierToken(s) => {
             let (call, len) = try_read_function_call(tokens, pos);
             match &call {
                 ExprAST::Error => return (ExprAST::Variable(s.to_owned()), Some(1)),
-                ExprAST::CallCallableObjectByname(_callee, _args) => {
+                ExprAST::CallCallableObjectByname(..) => {
                     return (call, Some(len))
                 },
                 _ => panic!("Unexpected read result for identifier!")
@@ -120,7 +133,7 @@ fn try_read_function_call(tokens: &Vec<Token>, pos: usize) -> (ExprAST, usize) {
     assert_eq!(tokens[pos+len], RightParentheses);
     len += 1;
 
-    (ExprAST::CallCallableObjectByname(func_name.to_owned(), parameters), len)
+    (ExprAST::CallCallableObjectByname(func_name.to_owned(), parameters, pos), len)
 }
 
 fn read_if_expr(tokens: &Vec<Token>, pos: usize) -> (IfElseExpr, usize) {
@@ -183,12 +196,12 @@ pub struct IntegerLiteral {
 #[derive(Clone)]
 pub struct IfElseExpr {
     pub condition: Box<ExprAST>,
-    pub then_case: BlockAST,
-    pub else_case: BlockAST
+    pub then_case: Rc<BlockAST>,
+    pub else_case: Rc<BlockAST>
 }
 
 #[derive(Clone)]
 pub struct ClosureAST{
     pub params: Vec<String>,
-    pub block: BlockAST
+    pub block: Rc<BlockAST>
 }
\ No newline at end of file
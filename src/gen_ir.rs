@@ -25,7 +25,7 @@ fn compile_fn<'a>(compiler: &Compiler<'a>, fnast: &FunctionAST) -> LLVMString{
     let builder = &compiler.builder;
 
     info!("building function {:?}", &fnast);
-    let return_type = match &*fnast.return_expr {
+    let return_type = match &*fnast.body.return_expr {
         ExprAST::Int(_) => context.i64_type(),
         _ => unimplemented!(),
     };
@@ -36,7 +36,7 @@ fn compile_fn<'a>(compiler: &Compiler<'a>, fnast: &FunctionAST) -> LLVMString{
     let entry = context.append_basic_block(fn_value, "entry");
     builder.position_at_end(entry);
 
-    let return_value = match &*fnast.return_expr {
+    let return_value = match &*fnast.body.return_expr {
         ExprAST::Int(x) =>
             context.i64_type().const_int( (*x) as u64, false),
         _ => unimplemented!(),
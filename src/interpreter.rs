@@ -1,398 +1,963 @@
+use std::any::Any;
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::io::BufReader;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::panic::AssertUnwindSafe;
 use std::rc::Rc;
-use log::{debug, info};
+use log::debug;
 use crate::builtin;
-use crate::parser::{BlockAST, function2block, FunctionAST, ProgramAST, StatementAST};
-use crate::parser::AST;
-use crate::debug_output::build_expr_debug_strings;
-use crate::expr::{ClosureAST, ExprAST};
+use crate::parser::{FileOpenAst, ProgramAST};
+use crate::env::Env;
+use crate::value::{Thunk, Value};
+use crate::bytecode::{BytecodeProgram, Proto};
+use crate::lower::{BlockCode, Callee, Code, FunctionCode, FunctionId, LoweredProgram, SiteId, StatementCode};
+use crate::native::Natives;
+use crate::io::{FileSystem, IoBackend};
+use crate::trace::{IoEvent, IoTrace};
 
 
-use crate::builtin::{IroncamelLinkedList, perform_write};
-use crate::interpreter::CallableObject::Closure;
+use crate::builtin::perform_write;
 
 
+// What a running program shares apart from its code, i.e. the same for the interpreter and the virtual machine
 pub struct GlobalState {
-    pub global_scope: HashMap<String,FunctionAST>,
-    pub open_file_list: HashMap<String, IroncamelFileInfo>
+    pub open_file_list: HashMap<String, IroncamelFileInfo>,
+    // Where stdin, stdout and the opened files come from
+    pub io: Box<dyn IoBackend>,
+    pub limits: Limits,
+    pub usage: Usage,
+    pub natives: Natives,
+    pub trace: Option<IoTrace>
 }
 
 impl GlobalState {
-    pub(crate) fn has_builtin_function(&self, func_name: &str) -> bool {
-        builtin::ARITHMETIC_OPERATORS.contains(&func_name) ||
-            builtin::IRONCAMEL_BUILTIN_FUNCTIONS.contains(&func_name)
+    // Reads and opens come from the trace instead of `io`
+    pub(crate) fn replaying(&self) -> Option<IoTrace> {
+        self.trace.clone().filter(|t| t.is_replaying())
     }
-    pub(crate) fn find_global_function(&self, func_name: &String) -> Option<&FunctionAST> {
-        self.global_scope.get(func_name)
+
+    pub(crate) fn recording(&self) -> Option<IoTrace> {
+        self.trace.clone().filter(|t| !t.is_replaying())
     }
 }
 
+// Nested non-tail calls, each one costs a few frames on the heap
+pub const DEFAULT_MAX_DEPTH: usize = 1_000_000;
+
+// Exceeding any of them stops the program with an error whose `limit` is set. None means unlimited
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_depth: usize,
+    // Calls of functions, closures and builtins (natives included), the same on the interpreter and the
+    // virtual machine. if, let and the special forms don't count, without calls a body only takes so long
+    pub max_steps: Option<u64>,
+    pub max_list_length: Option<usize>,
+    // List nodes allocated by builtins over the whole run, even those freed since
+    pub max_memory: Option<u64>
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { max_depth: DEFAULT_MAX_DEPTH, max_steps: None, max_list_length: None, max_memory: None }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Steps,
+    Depth,
+    ListLength,
+    Memory,
+}
+
+impl Limit {
+    // Only for a limit which is set
+    pub fn message(self, limits: &Limits) -> String {
+        match self {
+            Limit::Steps => format!("step limit exceeded: more than {} calls", limits.max_steps.unwrap()),
+            Limit::Depth => format!("recursion limit exceeded: more than {} nested calls", limits.max_depth),
+            Limit::ListLength => format!("list length limit exceeded: more than {} elements", limits.max_list_length.unwrap()),
+            Limit::Memory => format!("memory limit exceeded: more than {} list nodes allocated", limits.max_memory.unwrap()),
+        }
+    }
+}
+
+// What the program used so far, counted against the limits by both engines
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub steps: Cell<u64>,
+    pub list_nodes: Cell<u64>,
+}
+
 impl GlobalState {
-    pub(crate) fn has_identifier(&self, var: &String) -> bool {
-        if self.global_scope.contains_key(var) {
-            return true;
+    // Every call of a function, closure or builtin is a step, the virtual machine counts them on its own
+    #[inline(always)]
+    pub(crate) fn step(&self) -> Result<(), Limit> {
+        let steps = self.usage.steps.get() + 1;
+        self.usage.steps.set(steps);
+        match self.limits.max_steps {
+            Some(max) if steps > max => Err(Limit::Steps),
+            _ => Ok(())
+        }
+    }
+
+    // Counts the list nodes a builtin allocated for its result
+    #[inline(always)]
+    pub(crate) fn allocate(&self, func_name: &str, result: &Value) -> Result<(), Limit> {
+        if !matches!(result, Value::List(_) | Value::LazyCons(_)) {
+            return Ok(());
+        }
+        self.count_list_nodes(func_name, result)
+    }
+
+    fn count_list_nodes(&self, func_name: &str, result: &Value) -> Result<(), Limit> {
+        let nodes = builtin::allocated_list_nodes(func_name, result);
+        if nodes == 0 {
+            return Ok(());
+        }
+        if let (Some(max), Value::List(l)) = (self.limits.max_list_length, result)
+            && l.len > max {
+            return Err(Limit::ListLength);
+        }
+        let total = self.usage.list_nodes.get() + nodes as u64;
+        self.usage.list_nodes.set(total);
+        match self.limits.max_memory {
+            Some(max) if total > max => Err(Limit::Memory),
+            _ => Ok(())
         }
-        false
     }
 }
 
-#[derive(Clone)]
-pub enum CallableObject {
-    GlobalFunction(String),
-    BuiltinFunction(String),
-    Closure(Rc<ClosureAST>, Rc<HashMap<String,ExprAST>>),
+#[derive(Clone, Debug, Default)]
+pub struct EvalOptions {
+    pub limits: Limits,
+    // Call by need: let bindings and arguments are evaluated when needed, at most once.
+    // The same program gives the same output, unless it only terminates lazily
+    pub lazy: bool,
+    // Compile to bytecode and run it on the virtual machine (see vm.rs), which is strict only
+    pub vm: bool,
+    // Records the IO of main, or replays it (see trace.rs)
+    pub trace: Option<IoTrace>,
+    // The command line arguments, given to main if it takes a parameter
+    pub args: Vec<String>
 }
 
-pub fn eval(ast: &ProgramAST) -> i64 {
-    let mut global_scope = build_global_state(ast);
-    let main_ast = ast.functions.iter().find(
-        |&x| x.function_name == "main");
-    match main_ast {
-        None => panic!("function main not found!"),
-        _ => ()
+// An error raised by the running program, such as hd of an empty list or a non-boolean condition.
+// Errors found before execution are ResolveErrors
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    // Innermost call first
+    pub stack: Vec<StackFrame>,
+    // Set if the program was stopped by one of the Limits rather than failing on its own
+    pub limit: Option<Limit>
+}
+
+#[derive(Debug)]
+pub enum StackFrame {
+    // A function, closure or builtin, with its arguments shortened by Value::summary, and where it was called.
+    // A tail call replaces the frame of its caller, like it does during evaluation
+    Call { function: String, arguments: Vec<String>, location: String },
+    // main or a test, where it is defined
+    Entry { name: String, location: String },
+    // Calls left out of a very deep stack
+    Omitted(usize),
+}
+
+// The innermost and outermost calls kept in a stack trace
+const TRACE_INNERMOST: usize = 20;
+const TRACE_OUTERMOST: usize = 5;
+
+impl RuntimeError {
+    pub fn new(message: String) -> RuntimeError {
+        RuntimeError { message, stack: Vec::new(), limit: None }
     }
-    let main_ast = main_ast.unwrap();
-    debug!("main ast {:?}", main_ast.debug_strings());
-    execute_main_function(&mut global_scope, HashMap::new(), &main_ast);
-    0
 }
 
-fn execute_main_function(global: &mut GlobalState, mut local: HashMap<String, ExprAST>, fun: &FunctionAST) {
-    for s in &fun.statements {
-        match &s {
-            StatementAST::Bind(lb) => {
-                debug!("Try to process {:?}", lb.debug_strings());
-                let var = &lb.variable;
-                if global.has_identifier(var) || local.contains_key(var) {
-                    panic!("{} is already in env! No shadowing allowed!", var);
-                }
-                let expr_ast: &ExprAST = &lb.expr;
-                let expr = solve(&global, &local, expr_ast);
-                local.insert(var.to_owned(), expr);
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.stack {
+            match frame {
+                StackFrame::Call { function, arguments, location } => {
+                    write!(f, "\n    at {}({})", function, arguments.join(", "))?;
+                    write_location(f, location)?;
+                },
+                StackFrame::Entry { name, location } => {
+                    write!(f, "\n    at {}", name)?;
+                    write_location(f, location)?;
+                },
+                StackFrame::Omitted(n) => write!(f, "\n    ... {} more calls", n)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_location(f: &mut fmt::Formatter<'_>, location: &str) -> fmt::Result {
+    if location.is_empty() {
+        return Ok(());
+    }
+    write!(f, " ({})", location)
+}
+
+impl std::error::Error for RuntimeError {}
+
+// Run main and return its value
+pub fn eval(ast: &ProgramAST) -> Result<Value, RuntimeError> {
+    eval_with_options(ast, &EvalOptions::default())
+}
+
+pub fn eval_with_options(ast: &ProgramAST, options: &EvalOptions) -> Result<Value, RuntimeError> {
+    run_main(ast, Box::new(FileSystem::new()), options)
+}
+
+// Same as eval, but the program reads stdin@ and writes stdout@ through the given streams
+pub fn eval_with_stdio(ast: &ProgramAST, stdin: Box<dyn BufRead>, stdout: Box<dyn Write>) -> Result<Value, RuntimeError> {
+    run_main(ast, Box::new(FileSystem::with_stdio(stdin, stdout)), &EvalOptions::default())
+}
+
+pub fn eval_with_stdio_and_options(ast: &ProgramAST, stdin: Box<dyn BufRead>, stdout: Box<dyn Write>,
+                                   options: &EvalOptions) -> Result<Value, RuntimeError> {
+    run_main(ast, Box::new(FileSystem::with_stdio(stdin, stdout)), options)
+}
+
+// Every IO statement goes to `io`, e.g. a MemoryIo which never touches the disk
+pub fn eval_with_io(ast: &ProgramAST, io: Box<dyn IoBackend>, options: &EvalOptions) -> Result<Value, RuntimeError> {
+    run_main(ast, io, options)
+}
+
+// Run main of a compiled program, e.g. one loaded from an .icmlc artifact, on the virtual machine
+pub fn eval_bytecode(program: &BytecodeProgram, options: &EvalOptions) -> Result<Value, RuntimeError> {
+    eval_bytecode_with_io(program, Box::new(FileSystem::new()), options)
+}
+
+pub fn eval_bytecode_with_stdio(program: &BytecodeProgram, stdin: Box<dyn BufRead>, stdout: Box<dyn Write>,
+                                options: &EvalOptions) -> Result<Value, RuntimeError> {
+    eval_bytecode_with_io(program, Box::new(FileSystem::with_stdio(stdin, stdout)), options)
+}
+
+pub fn eval_bytecode_with_io(program: &BytecodeProgram, io: Box<dyn IoBackend>,
+                             options: &EvalOptions) -> Result<Value, RuntimeError> {
+    assert!(!options.lazy, "The virtual machine doesn't evaluate lazily");
+    let mut global_scope = build_global_state(io, options.limits.clone(), Natives::default(), options.trace.clone());
+    let main = match &program.main {
+        Some(main) => main,
+        None => panic!("function main not found!")
+    };
+    crate::vm::execute_entry(&mut global_scope, program, main, "main", main_arguments(main.arity, &options.args))
+}
+
+fn lower(ast: &ProgramAST, options: &EvalOptions) -> LoweredProgram {
+    if options.lazy {
+        crate::lower::lower_program_lazily(ast)
+    } else {
+        crate::lower::lower_program(ast)
+    }
+}
+
+fn run_main(ast: &ProgramAST, io: Box<dyn IoBackend>, options: &EvalOptions) -> Result<Value, RuntimeError> {
+    let program = lower(ast, options);
+    if options.vm {
+        return eval_bytecode_with_io(&crate::bytecode::compile_program(&program), io, options);
+    }
+    let mut global_scope = build_global_state(io, options.limits.clone(), Natives::default(), options.trace.clone());
+    let main = match &program.main {
+        Some(main) => main,
+        None => panic!("function main not found!")
+    };
+    execute_main_function(&program, &mut global_scope, main, "main", main_arguments(main.arity, &options.args))
+}
+
+pub struct TestOutcome {
+    pub name: String,
+    // None if the test passed
+    pub failure: Option<String>
+}
+
+// Every test runs in a fresh global state, as if it were the main function.
+// A test fails on a runtime error (e.g. a failed assert_eq) or if it evaluates to false.
+// A panic is an interpreter bug, it fails the test instead of aborting the others
+pub fn run_tests(ast: &ProgramAST, options: &EvalOptions) -> Vec<TestOutcome> {
+    let program = lower(ast, options);
+    let bytecode = options.vm.then(|| crate::bytecode::compile_program(&program));
+    let default_hook = std::panic::take_hook();
+    // The panic message is reported by the caller, not printed by the default hook
+    std::panic::set_hook(Box::new(|_| {}));
+    let mut outcomes = Vec::with_capacity(program.tests.len());
+    for (i, test) in program.tests.iter().enumerate() {
+        let compiled = bytecode.as_ref().map(|b| (b, &b.tests[i]));
+        let failure = run_single_test(&program, test, compiled, &options.limits);
+        outcomes.push(TestOutcome{ name: test.name.to_owned(), failure });
+    }
+    std::panic::set_hook(default_hook);
+    outcomes
+}
+
+// `compiled` is the same test for the virtual machine
+fn run_single_test(program: &LoweredProgram, test: &FunctionCode, compiled: Option<(&BytecodeProgram, &Proto)>,
+                   limits: &Limits) -> Option<String> {
+    debug!("Running test {}", &test.name);
+    let mut global_scope = build_global_state(Box::new(FileSystem::new()), limits.clone(), Natives::default(), None);
+    let entry = format!("test {:?}", test.name);
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| match compiled {
+        Some((bytecode, proto)) => crate::vm::execute_entry(&mut global_scope, bytecode, proto, &entry, Vec::new()),
+        None => execute_main_function(program, &mut global_scope, test, &entry, Vec::new())
+    }));
+    match result {
+        Ok(Ok(Value::Bool(false))) => Some(String::from("test evaluated to false")),
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(payload) => Some(panic_message(payload))
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<String>() {
+        s.to_owned()
+    } else if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+// `entry` names main or the test at the bottom of a stack trace
+// `fn main(args)` gets the list of command line arguments
+pub(crate) fn main_arguments(arity: usize, args: &[String]) -> Vec<Value> {
+    match arity {
+        0 => Vec::new(),
+        _ => vec![Value::from(args.to_vec())]
+    }
+}
+
+pub(crate) fn execute_main_function(program: &LoweredProgram, global: &mut GlobalState, main: &FunctionCode,
+                         entry: &str, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    execute_main_body(program, global, main, arguments).map_err(|mut e| {
+        e.stack.push(StackFrame::Entry { name: entry.to_owned(), location: main.location.to_owned() });
+        e
+    })
+}
+
+// Statements with IO are only allowed here. Tests are executed the same way.
+// What is written and returned is evaluated completely, even in the lazy mode
+fn execute_main_body(program: &LoweredProgram, global: &mut GlobalState, main: &FunctionCode,
+                     arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let mut local = Env::new(None, main.arity + main.body.statements.len());
+    for value in arguments {
+        local.push(value);
+    }
+    for s in &main.body.statements {
+        match s {
+            StatementCode::Let(code) => {
+                let expr = solve(program, global, &local, code)?;
+                local.push(expr);
             },
-            StatementAST::Write(write) => {
+            StatementCode::Write { procedure, file_handler, code } => {
                 debug!("Trying to process write");
-                let expr = solve(&global, &local, &write.expr);
-                perform_write(&write.impure_procedure_name, &write.file_handler,
-                              &expr, global);
+                let expr = solve_completely(program, global, &local, code)?;
+                perform_write(procedure, file_handler, &expr, global).map_err(RuntimeError::new)?;
             },
-            StatementAST::FileOpen(fo) => {
-                match fo.impure_procedure_name.as_str() {
-                    "fopen_read" => {
-                        let fin = std::fs::File::open(&fo.file_path).expect("file not found");
-                        let reader = BufReader::new(fin);
-                        let f_data = IroncamelFileInfo::FileRead(reader);
-                        global.open_file_list.insert(fo.file_handler.to_owned(), f_data);
-                        debug!("Open file {} as handler {}", fo.file_path, fo.file_handler);
-                    },
-                    "fopen_write" => {
-                        let fout = std::fs::File::create(&fo.file_path).expect("Create file failed");
-                        let f_data = IroncamelFileInfo::FileWrite(fout);
-                        global.open_file_list.insert(fo.file_handler.to_owned(), f_data);
-                        debug!("Open file {} as handler {}", fo.file_path, fo.file_handler);
-                    },
-                    _ => {
-                        panic!("No such FileOpen procedure! {}", fo.impure_procedure_name.as_str());
-                    }
-                }
-            },
-            StatementAST::Read(r) => {
-                let expr = builtin::perform_read(&r.impure_procedure_name, &r.file_handler, global);
-                let var = &r.write_to_variable;
-                if global.has_identifier(var) || local.contains_key(var) {
-                    panic!("{} is already in env! No shadowing allowed!", var);
-                }
-                local.insert(var.to_owned(), expr);
+            StatementCode::FileOpen(fo) => open_file(global, fo)?,
+            StatementCode::Read { procedure, file_handler, variable } => {
+                let expr = builtin::perform_read(procedure, file_handler, variable, global).map_err(RuntimeError::new)?;
+                local.push(expr);
             }
-            _ => panic!("Not supported other statements!"),
         }
     }
+    solve_completely(program, global, &local, &main.body.return_code)
 }
 
+// A global function called by the host like main, see embed.rs. The number of arguments is checked by the caller
+pub(crate) fn call_function(program: &LoweredProgram, global: &GlobalState, id: FunctionId,
+                            arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let fun = &program.functions[id];
+    let mut env = Env::new(None, fun.arity + fun.body.statements.len());
+    for value in arguments {
+        env.push(value);
+    }
+    let mut machine = Machine { program, global, stack: Vec::new(), depth: 0, error: None };
+    let task = machine.enter_block(env, &fun.body.statements, 0, &fun.body.return_code);
+    machine.run_task(task)
+        .and_then(|value| machine.evaluate_completely(&value))
+        .map_err(|mut e| {
+            e.stack.push(StackFrame::Entry { name: fun.name.to_owned(), location: fun.location.to_owned() });
+            e
+        })
+}
 
-fn build_global_state(ast: &ProgramAST) -> GlobalState {
-    let global_functions = process_global_functions(ast);
+pub(crate) fn open_file(global: &mut GlobalState, fo: &FileOpenAst) -> Result<(), RuntimeError> {
+    let procedure = fo.impure_procedure_name.as_str();
+    let opened = match global.replaying() {
+        Some(trace) => replay_open_file(&trace, fo).map_err(RuntimeError::new)?,
+        None => open_real_file(global, fo)
+    };
+    if let Some(trace) = global.recording() {
+        trace.record_event(IoEvent::Open {
+            procedure: procedure.to_owned(),
+            handler: fo.file_handler.to_owned(),
+            path: fo.file_path.to_owned(),
+            error: opened.as_ref().err().cloned()
+        });
+    }
+    let f_data = opened.map_err(RuntimeError::new)?;
+    global.open_file_list.insert(fo.file_handler.to_owned(), f_data);
+    debug!("Open file {} as handler {}", fo.file_path, fo.file_handler);
+    Ok(())
+}
+
+fn open_real_file(global: &mut GlobalState, fo: &FileOpenAst) -> Result<IroncamelFileInfo, String> {
+    match fo.impure_procedure_name.as_str() {
+        "fopen_read" => global.io.open_read(&fo.file_path)
+            .map(IroncamelFileInfo::FileRead)
+            .map_err(|e| format!("Can't open {}: {}", fo.file_path, e)),
+        "fopen_write" => global.io.open_write(&fo.file_path)
+            .map(IroncamelFileInfo::FileWrite)
+            .map_err(|e| format!("Can't create {}: {}", fo.file_path, e)),
+        _ => {
+            panic!("No such FileOpen procedure! {}", fo.impure_procedure_name.as_str());
+        }
+    }
+}
+
+// Nothing is read from or written to the file itself, the reads come from the trace too
+fn replay_open_file(trace: &IoTrace, fo: &FileOpenAst) -> Result<Result<IroncamelFileInfo, String>, String> {
+    let error = trace.replay_open(&fo.impure_procedure_name, &fo.file_handler, &fo.file_path)?;
+    Ok(match (error, fo.impure_procedure_name.as_str()) {
+        (Some(e), _) => Err(e),
+        (None, "fopen_read") => Ok(IroncamelFileInfo::FileRead(Box::new(std::io::empty()))),
+        (None, _) => Ok(IroncamelFileInfo::FileWrite(Box::new(std::io::sink())))
+    })
+}
+
+pub(crate) fn build_global_state(mut io: Box<dyn IoBackend>, limits: Limits, natives: Natives,
+                                 trace: Option<IoTrace>) -> GlobalState {
     let mut open_file_list =  HashMap::new();
-    open_file_list.insert("stdin".to_owned(), IroncamelFileInfo::Stdin);
-    open_file_list.insert("stdout".to_owned(), IroncamelFileInfo::Stdout);
+    open_file_list.insert("stdin".to_owned(), IroncamelFileInfo::Stdin(io.stdin()));
+    open_file_list.insert("stdout".to_owned(), IroncamelFileInfo::Stdout(io.stdout()));
     GlobalState {
-        global_scope: global_functions,
-        open_file_list
-    }
-}
-fn execute_block_with_consumable_env(global: &GlobalState,
-                                     mut local: HashMap<String, ExprAST>,
-                                     exec: &BlockAST, allow_io: bool) -> ExprAST{
-    assert!(!allow_io);
-    for s in &exec.statements {
-        match &s {
-            StatementAST::Bind(lb) => {
-                debug!("Try to process {:?}", lb.debug_strings());
-                let var = &lb.variable;
-                if global.has_identifier(var) || local.contains_key(var) {
-                    panic!("{} is already in env! No shadowing allowed!", var);
-                }
-                let expr_ast: &ExprAST = &lb.expr;
-                let expr = solve(&global, &local, expr_ast);
-                local.insert(var.to_owned(), expr);
-            },
-            _ => panic!("Not supported other statements!"),
-        }
+        open_file_list,
+        io,
+        limits,
+        usage: Usage::default(),
+        natives,
+        trace
     }
-    solve(global, &mut local, &exec.return_expr)
 }
 
-fn execute_block(global: &GlobalState,
-                 local: &HashMap<String, ExprAST>,
-                 exec: &BlockAST, allow_io: bool) -> ExprAST{
-    if exec.statements.len() == 0 {
-        // info!("Fast solve block {:?}", exec.return_expr);
-        // return lazy_solve_no_update(global, local, &exec.return_expr)
-        debug!("TODO: Avoid unnecessary local env copy");
+
+/* The evaluator
+    Recursion is the only way to iterate in IronCamel, and plenty of it isn't in tail position
+    (append, apply, map in core.icml), so solve doesn't recurse on the Rust stack at all.
+    Everything still waiting for a value lives in Machine.stack on the heap:
+        Let          the rest of a block, waiting for the value of a let binding
+        If           the branches, waiting for the condition
+        Arguments    a call, waiting for its next parameter
+        CallReturn   the body of a function or closure is running
+    and in the lazy mode
+        Memo         a thunk is being forced, its value will be kept
+        Callee       a call, waiting for the thunk it calls
+        Force        a builtin, waiting for one of its params
+    A call in tail position (the return expression of a body, or either branch of an if) finds CallReturn
+    on top of the stack and replaces it instead of pushing another one, so tail calls run in constant space.
+    Only CallReturn frames count against Limits.max_depth, and only they show up in a stack trace.
+*/
+enum Task<'a> {
+    Eval(&'a Code, Env),
+    Return(Value),
+    // Machine.error is set
+    Fail,
+}
+
+enum RuntimeCallee<'a> {
+    Function(&'a FunctionCode),
+    Builtin(&'static str),
+    Closure(&'a FunctionCode, Env),
+}
+
+enum Frame<'a> {
+    // statements[next] is a let binding
+    Let { env: Env, statements: &'a [StatementCode], next: usize, return_code: &'a Code },
+    If { then_case: &'a BlockCode, else_case: &'a BlockCode, env: Env },
+    // Waiting for params[solved.len()]. `site` is where the call is, for stack traces
+    Arguments { callee: RuntimeCallee<'a>, params: &'a [Code], solved: Vec<Value>, env: Env, site: SiteId },
+    // The arguments and the site are only kept for stack traces
+    CallReturn { function: &'a FunctionCode, arguments: Vec<Value>, site: SiteId },
+    Memo(Rc<Thunk>),
+    Callee { params: &'a [Code], env: Env, site: SiteId },
+    // Waiting for params[index]
+    Force { func_name: &'static str, params: Vec<Value>, index: usize, site: SiteId },
+}
+
+struct Machine<'a> {
+    program: &'a LoweredProgram,
+    global: &'a GlobalState,
+    stack: Vec<Frame<'a>>,
+    depth: usize,
+    // Kept out of Task, a Result on every step slows the evaluator down noticeably
+    error: Option<RuntimeError>,
+}
+
+fn solve(program: &LoweredProgram, global: &GlobalState, local: &Env, code: &Code) -> Result<Value, RuntimeError> {
+    let mut machine = Machine { program, global, stack: Vec::new(), depth: 0, error: None };
+    machine.run(code, local.clone())
+}
+
+// Without any thunk left inside
+fn solve_completely(program: &LoweredProgram, global: &GlobalState, local: &Env, code: &Code) -> Result<Value, RuntimeError> {
+    let mut machine = Machine { program, global, stack: Vec::new(), depth: 0, error: None };
+    let value = machine.run(code, local.clone())?;
+    machine.evaluate_completely(&value)
+}
+
+// Whether evaluate_completely has anything to do
+fn has_thunks(value: &Value) -> bool {
+    match value {
+        Value::Thunk(_) | Value::LazyCons(_) => true,
+        Value::List(l) => l.iter().any(has_thunks),
+        _ => false
     }
-    execute_block_with_consumable_env(global, local.clone(), exec, allow_io)
 }
 
+fn summarize(values: &[Value]) -> Vec<String> {
+    values.iter().map(|v| v.summary()).collect()
+}
 
-fn execute_function(global: &GlobalState, fun: &FunctionAST, params: &Vec<ExprAST>,
-                    allow_io: bool) -> ExprAST{
-    assert_eq!(fun.arguments.len(), params.len());
-    let mut new_env = HashMap::new();
-    for i in 0..fun.arguments.len() {
-        let var_name = &fun.arguments[i];
-        new_env.insert(var_name.to_owned(), params[i].to_owned());
+// The running calls, innermost first, as a stack trace. The middle of a very deep stack is left out
+pub(crate) fn call_trace<T>(calls: &[T], frame: impl Fn(&T) -> StackFrame) -> Vec<StackFrame> {
+    let omitted = calls.len().saturating_sub(TRACE_INNERMOST + TRACE_OUTERMOST);
+    let mut stack = Vec::new();
+    for (i, call) in calls.iter().enumerate() {
+        if omitted > 0 && i == TRACE_INNERMOST {
+            stack.push(StackFrame::Omitted(omitted));
+        }
+        if omitted > 0 && i >= TRACE_INNERMOST && i < TRACE_INNERMOST + omitted {
+            continue;
+        }
+        stack.push(frame(call));
     }
-    execute_block_with_consumable_env(
-        global, new_env,
-        &function2block(fun.clone()), allow_io)
+    stack
 }
 
+pub(crate) fn call_frame(function: &str, arguments: &[Value], location: &str) -> StackFrame {
+    StackFrame::Call { function: function.to_owned(), arguments: summarize(arguments), location: location.to_owned() }
+}
 
-fn solve(global: &GlobalState, local: &HashMap<String, ExprAST>,
-         ast: &ExprAST) -> ExprAST {
+impl<'a> Machine<'a> {
+    #[inline(always)]
+    fn run(&mut self, code: &'a Code, env: Env) -> Result<Value, RuntimeError> {
+        self.run_task(Task::Eval(code, env))
+    }
 
-    debug!("Eager solving {:?} with env {:?}", build_expr_debug_strings(&ast), local.keys());
+    // The hot loop, fib gets noticeably slower when it is shared by the callers
+    #[inline(always)]
+    fn run_task(&mut self, mut task: Task<'a>) -> Result<Value, RuntimeError> {
+        loop {
+            task = match task {
+                Task::Eval(code, env) => self.eval(code, env),
+                Task::Return(value) => match self.stack.pop() {
+                    Some(frame) => self.resume(frame, value),
+                    None => return Ok(value)
+                },
+                Task::Fail => return Err(self.error.take().unwrap())
+            };
+        }
+    }
 
-    // info!("Local env {:?}", local.keys());
-    let result = match ast {
-        ExprAST::Int(_) |  ExprAST::Bool(_) | ExprAST::StringLiteral(_) => ast.clone(),
-        // TODO the implementation for lookup is not correct
-        ExprAST::Variable(v) => {
-            if global.global_scope.contains_key(v) {
-                return ExprAST::Callable(CallableObject::GlobalFunction(v.clone()));
-            }
-            if global.has_builtin_function(v) {
-                return ExprAST::Callable(CallableObject::BuiltinFunction(v.clone()));
-                // return lazy_solve(global, local,
-                //                   &ExprAST::Callable(CallableObject::GlobalFunction(v.clone())));
-            }
-            let result = lookup_local_variable(global, local, v);
-            result
+    // Stop with the calls still running as the stack trace
+    #[cold]
+    fn fail(&mut self, message: String) -> Task<'a> {
+        self.error = Some(self.error_with_trace(message));
+        Task::Fail
+    }
+
+    fn error_with_trace(&self, message: String) -> RuntimeError {
+        let calls: Vec<(&FunctionCode, &Vec<Value>, SiteId)> = self.stack.iter().rev().filter_map(|frame| match frame {
+            Frame::CallReturn { function, arguments, site } => Some((*function, arguments, *site)),
+            _ => None
+        }).collect();
+        let stack = call_trace(&calls, |(function, arguments, site)| {
+            call_frame(&function.name, arguments, &self.program.sites[*site])
+        });
+        RuntimeError { message, stack, limit: None }
+    }
+
+    #[cold]
+    fn fail_limit(&mut self, limit: Limit) -> Task<'a> {
+        let mut error = self.error_with_trace(limit.message(&self.global.limits));
+        error.limit = Some(limit);
+        self.error = Some(error);
+        Task::Fail
+    }
+
+    fn eval(&mut self, code: &'a Code, env: Env) -> Task<'a> {
+        match code {
+            Code::Const(v) => Task::Return(v.clone()),
+            Code::Local { depth, slot } => Task::Return(env.get(*depth, *slot).clone()),
+            Code::Function(id) => Task::Return(Value::Function(*id)),
+            Code::Builtin(name) => Task::Return(Value::Builtin(name)),
+            Code::Call(callee, params, site) => match self.find_callee(&env, callee) {
+                Ok(callee) => self.solve_arguments(callee, params, Vec::with_capacity(params.len()), env, *site),
+                Err(Value::Thunk(t)) => {
+                    let t = t.clone();
+                    self.stack.push(Frame::Callee { params, env: env.clone(), site: *site });
+                    self.force(t)
+                },
+                Err(x) => self.fail(format!("Expect a callable object, got {}", x.summary()))
+            },
+            Code::If(condition, then_case, else_case) => {
+                self.stack.push(Frame::If { then_case, else_case, env: env.clone() });
+                Task::Eval(condition, env)
+            },
+            // Left by the optimizer when an if branch is pruned
+            Code::Block(block) => self.enter_nested_block(env, block),
+            Code::Closure(id) => Task::Return(Value::Closure(*id, env)),
+            Code::Delay(id) => Task::Return(Value::Thunk(Rc::new(Thunk::new(*id, env)))),
         }
+    }
 
-        ExprAST::CallCallableObjectByname(func_name, params) => {
-            // Is this a local function?
-            let callee : ExprAST = find_callee(global, local, func_name, params);
-            solve(global, local, &callee)
+    // The Memo frame keeps the value, and forces it again if it is another thunk
+    fn force(&mut self, thunk: Rc<Thunk>) -> Task<'a> {
+        match thunk.delayed() {
+            Some((id, env)) => {
+                self.stack.push(Frame::Memo(thunk));
+                Task::Eval(&self.program.thunks[id], env)
+            },
+            None => Task::Return(thunk.value().unwrap())
         }
-        ExprAST::If(if_expr) => {
-            let cond = solve(global, local, &if_expr.condition);
-            let cond = match cond {
-                ExprAST::Bool(x) => x,
-                _ => panic!("Expect a boolean value, got {:?}", build_expr_debug_strings(&cond))
+    }
+
+    // A thunk met outside of the stack (in a list given to a builtin or written) is forced on a machine
+    // of its own. Only the lists are walked through here, the thunks themselves don't use the Rust stack
+    fn evaluate_completely(&self, value: &Value) -> Result<Value, RuntimeError> {
+        if !has_thunks(value) {
+            return Ok(value.clone());
+        }
+        let mut items = Vec::new();
+        let mut rest = value.clone();
+        loop {
+            rest = match rest {
+                Value::Thunk(t) => match t.value() {
+                    Some(v) => v,
+                    None => self.force_apart(t)?
+                },
+                Value::LazyCons(c) => {
+                    items.push(self.evaluate_completely(&c.head)?);
+                    c.tail.clone()
+                },
+                Value::List(l) => {
+                    for v in l.iter() {
+                        items.push(self.evaluate_completely(v)?);
+                    }
+                    break;
+                },
+                // Not a list, only possible for the value itself or a broken tail
+                v if items.is_empty() => return Ok(v),
+                v => return Err(self.error_with_trace(format!("Expect a list as the tail, got {}", v.summary())))
             };
-            let selected = if cond { &if_expr.then_case} else { &if_expr.else_case};
-            // for s in build_expr_debug_strings(ast) {eprintln!("{}",s);}
-            // info!("Condition is {}", cond);
-            // info!("Selected {:?}", selected.debug_strings());
-            // info!("Local env is {:?}", local);
-            execute_block(global, local, selected, false)
-        },
-        ExprAST::CallBuiltinFunction(func_name, params) => {
-            let mut solved_params = Vec::with_capacity(params.len());
-            for p in params {
-                let rp = solve(global, local, &p);
-                solved_params.push(rp);
+        }
+        Ok(Value::list(&items))
+    }
+
+    fn force_apart(&self, thunk: Rc<Thunk>) -> Result<Value, RuntimeError> {
+        let (id, env) = match thunk.delayed() {
+            Some(delayed) => delayed,
+            None => return Ok(thunk.value().unwrap())
+        };
+        let mut machine = Machine {
+            program: self.program, global: self.global, stack: vec![Frame::Memo(thunk)], depth: self.depth, error: None
+        };
+        machine.run(&self.program.thunks[id], env).map_err(|mut e| {
+            e.stack.extend(self.error_with_trace(String::new()).stack);
+            e
+        })
+    }
+
+    fn resume(&mut self, frame: Frame<'a>, value: Value) -> Task<'a> {
+        match frame {
+            Frame::Let { mut env, statements, next, return_code } => {
+                env.push(value);
+                self.enter_block(env, statements, next + 1, return_code)
+            },
+            Frame::If { then_case, else_case, env } => {
+                let cond = match value {
+                    Value::Bool(x) => x,
+                    Value::Thunk(t) => {
+                        self.stack.push(Frame::If { then_case, else_case, env });
+                        return self.force(t);
+                    },
+                    _ => return self.fail(format!("Expect a boolean value, got {}", value.summary()))
+                };
+                self.enter_nested_block(env, if cond { then_case } else { else_case })
+            },
+            Frame::Arguments { callee, params, mut solved, env, site } => {
+                solved.push(value);
+                self.solve_arguments(callee, params, solved, env, site)
+            },
+            Frame::CallReturn { .. } => {
+                self.depth -= 1;
+                Task::Return(value)
+            },
+            Frame::Memo(thunk) => match value {
+                Value::Thunk(inner) => {
+                    self.stack.push(Frame::Memo(thunk));
+                    self.force(inner)
+                },
+                _ => {
+                    thunk.set(value.clone());
+                    Task::Return(value)
+                }
+            },
+            Frame::Callee { params, env, site } => match self.callee_of(&value) {
+                Some(callee) => self.solve_arguments(callee, params, Vec::with_capacity(params.len()), env, site),
+                None => self.fail(format!("Expect a callable object, got {}", value.summary()))
+            },
+            Frame::Force { func_name, mut params, index, site } => {
+                params[index] = value;
+                self.apply_builtin(func_name, params, site)
             }
-            builtin::call_builtin_function(&func_name, solved_params)
-        },
-        ExprAST::List(list) => {
-            ExprAST::List(solve_list(global, local, list))
-        },
-
-        ExprAST::Closure(clos) => {
-            ExprAST::Callable(Closure(
-                clos.clone(),
-                Rc::new(local.clone())
-            ))
-        },
-        // _ => {
-        //     panic!("Not supported ast yet : {:?}", build_expr_debug_strings(ast));
-        // }
-        ExprAST::Block(_) => { panic!("Not supported ast yet : {:?}", build_expr_debug_strings(ast)) }
-        ExprAST::Error => {panic!("Error!")},
-        ExprAST::Callable(_) => {
-            ast.clone()
         }
-    };
-    debug!("solving {:?} -> {:?}", build_expr_debug_strings(ast), result);
-    result
-}
+    }
 
-fn solve_list(global: &GlobalState, local: &HashMap<String, ExprAST>,
-              head: &Rc<IroncamelLinkedList>) -> Rc<IroncamelLinkedList>{
-    if head.len == 0 {
-        return Rc::new(IroncamelLinkedList::build_empty_list())
+    // A block with let bindings gets its own frame, like lower_nested_block decided
+    fn enter_nested_block(&mut self, env: Env, block: &'a BlockCode) -> Task<'a> {
+        if block.statements.is_empty() {
+            return Task::Eval(&block.return_code, env);
+        }
+        let env = Env::new(Some(env), block.statements.len());
+        self.enter_block(env, &block.statements, 0, &block.return_code)
     }
-    let solved_head = solve(global, local, head.hd());
-    match head.tl() {
-        Some(t) => {
-            let rest = solve_list(global, local, &t);
-            Rc::new(IroncamelLinkedList::cons(solved_head, &rest))
+
+    fn enter_block(&mut self, env: Env, statements: &'a [StatementCode], next: usize,
+                   return_code: &'a Code) -> Task<'a> {
+        match statements.get(next) {
+            None => Task::Eval(return_code, env),
+            Some(StatementCode::Let(code)) => {
+                self.stack.push(Frame::Let { env: env.clone(), statements, next, return_code });
+                Task::Eval(code, env)
+            },
+            Some(_) => panic!("Not supported other statements!"),
         }
-        None => {
-            Rc::new(IroncamelLinkedList::build(solved_head))
+    }
+
+    fn solve_arguments(&mut self, callee: RuntimeCallee<'a>, params: &'a [Code],
+                       solved: Vec<Value>, env: Env, site: SiteId) -> Task<'a> {
+        if solved.len() == params.len() {
+            return self.apply(callee, solved, site);
         }
+        let next = &params[solved.len()];
+        self.stack.push(Frame::Arguments { callee, params, solved, env: env.clone(), site });
+        Task::Eval(next, env)
     }
 
-}
+    fn apply(&mut self, callee: RuntimeCallee<'a>, params: Vec<Value>, site: SiteId) -> Task<'a> {
+        let (fun, parent) = match callee {
+            RuntimeCallee::Builtin(func_name) => return self.apply_builtin(func_name, params, site),
+            RuntimeCallee::Function(fun) => (fun, None),
+            RuntimeCallee::Closure(fun, captured) => (fun, Some(captured))
+        };
+        if let Err(limit) = self.global.step() {
+            return self.fail_limit(limit);
+        }
+        // Only possible through a variable, direct calls are checked before execution
+        if fun.arity != params.len() {
+            return self.fail(format!("{} expects {} arguments, got {}", fun.name, fun.arity, params.len()));
+        }
+        let mut env = Env::new(parent, fun.arity + fun.body.statements.len());
+        for value in &params {
+            env.push(value.clone());
+        }
+        if !self.enter_call(fun, params, site) {
+            return self.fail_limit(Limit::Depth);
+        }
+        self.enter_block(env, &fun.body.statements, 0, &fun.body.return_code)
+    }
 
-fn find_callee(global: &GlobalState, local: &HashMap<String, ExprAST>, func_name: &String, params: &Vec<Box<ExprAST>>) -> ExprAST {
-    match local.get(func_name) {
-        Some(x) => {
-            let callee = match x {
-                ExprAST::Callable(co) => co,
-                _ => panic!("Expect a callable object, got {:?}", x)
-            };
-            let solved_params = solve_parameters(global, local, params);
-            return match callee {
-                CallableObject::GlobalFunction(f) => {
-                    ExprAST::CallCallableObjectByname(f.to_owned(),
-                                                      box_expr(&solved_params))
+    fn apply_builtin(&mut self, func_name: &'static str, mut params: Vec<Value>, site: SiteId) -> Task<'a> {
+        // Only a lazily lowered program has thunks
+        if !self.program.thunks.is_empty() {
+            while let Some(index) = params.iter().position(|p| matches!(p, Value::Thunk(_))) {
+                if builtin::is_lazy_builtin(func_name) {
+                    break;
                 }
-                CallableObject::BuiltinFunction(f) => {
-                    ExprAST::CallBuiltinFunction(f.to_owned(),
-                                                 box_expr(
-                                                     &solved_params))
+                let thunk = match &params[index] {
+                    Value::Thunk(t) => t.clone(),
+                    _ => unreachable!()
+                };
+                match thunk.value() {
+                    Some(v) => params[index] = v,
+                    None => {
+                        self.stack.push(Frame::Force { func_name, params, index, site });
+                        return self.force(thunk);
+                    }
                 }
-                CallableObject::Closure(clos, local_env) => {
-                    let mut local_env_new = (**local_env).clone();
-                    assert_eq!(clos.params.len(), solved_params.len());
-                    for i in 0..solved_params.len() {
-                        local_env_new.insert(clos.params[i].to_owned(), solved_params[i].to_owned());
+            }
+            if builtin::looks_into_lists(func_name) {
+                for p in params.iter_mut() {
+                    match self.evaluate_completely(p) {
+                        Ok(v) => *p = v,
+                        Err(e) => {
+                            self.error = Some(e);
+                            return Task::Fail;
+                        }
                     }
-                    execute_block_with_consumable_env(global, local_env_new, &clos.block, false)
                 }
-            };
+            }
         }
-        None => { debug!("Not found variable ({}) in local scope", func_name)}
+        let result = self.global.step().map(|()| builtin::call_builtin_function(func_name, &params, &self.global.natives));
+        let (message, limit) = match result {
+            Ok(Ok(value)) => match self.global.allocate(func_name, &value) {
+                Ok(()) => return Task::Return(value),
+                Err(limit) => (limit.message(&self.global.limits), Some(limit))
+            },
+            Ok(Err(message)) => (message, None),
+            Err(limit) => (limit.message(&self.global.limits), Some(limit))
+        };
+        let mut error = self.error_with_trace(message);
+        error.stack.insert(0, call_frame(func_name, &params, &self.program.sites[site]));
+        error.limit = limit;
+        self.error = Some(error);
+        Task::Fail
     }
-    match global.has_builtin_function(func_name) {
-        true => {
-            let lazy_solved_params = solve_parameters(global, local, params);
 
-            return ExprAST::CallBuiltinFunction(func_name.to_owned(),
-                                                box_expr(&lazy_solved_params));
-        },
-        false =>  { debug!("Not a builtin function ({}) ", func_name)}
+    // A call in tail position replaces the one it returns from. False if the recursion limit is hit
+    fn enter_call(&mut self, fun: &'a FunctionCode, params: Vec<Value>, site: SiteId) -> bool {
+        if let Some(Frame::CallReturn { function, arguments, site: call_site }) = self.stack.last_mut() {
+            *function = fun;
+            *arguments = params;
+            *call_site = site;
+            return true;
+        }
+        if self.depth >= self.global.limits.max_depth {
+            return false;
+        }
+        self.depth += 1;
+        self.stack.push(Frame::CallReturn { function: fun, arguments: params, site });
+        true
     }
-    match global.find_global_function(func_name) {
-        Some(fun) => {
 
-            return execute_function(global, fun,
-                                    &solve_parameters(global, local, params), false);
+    // The value found if it isn't callable, or a thunk to force first
+    fn find_callee<'e>(&self, env: &'e Env, callee: &Callee) -> Result<RuntimeCallee<'a>, &'e Value> {
+        let program = self.program;
+        match callee {
+            Callee::Function(id) => Ok(RuntimeCallee::Function(&program.functions[*id])),
+            Callee::Builtin(name) => Ok(RuntimeCallee::Builtin(name)),
+            Callee::Local { depth, slot } => {
+                let value = env.get(*depth, *slot);
+                self.callee_of(value).ok_or(value)
+            }
         }
-        None  => { info!("Not found variable ({}) in local scope", func_name)}
     }
-    panic!("Can't find a callable object called ({})", func_name)
-}
 
-fn box_expr(input: &Vec<ExprAST>) -> Vec<Box<ExprAST>> {
-    let mut result = Vec::with_capacity(input.len());
-    for x in input {
-        result.push(Box::new(x.to_owned()));
+    fn callee_of(&self, value: &Value) -> Option<RuntimeCallee<'a>> {
+        let program = self.program;
+        match value {
+            Value::Function(id) => Some(RuntimeCallee::Function(&program.functions[*id])),
+            Value::Builtin(name) => Some(RuntimeCallee::Builtin(name)),
+            Value::Closure(id, captured) => Some(RuntimeCallee::Closure(&program.closures[*id], captured.clone())),
+            Value::Thunk(t) => t.value().and_then(|v| self.callee_of(&v)),
+            _ => None
+        }
     }
-    result
 }
 
-// This function is not lazy enough
-fn lookup_local_variable(global: &GlobalState, local: &HashMap<String, ExprAST>, v: &str) -> ExprAST {
-    let x = match local.get(v) {
-        Some(a) => a,
-        None =>{ panic!("Not found variable ({}) in local scope", v)}
-    };
-    let x = x.clone();
-
-    // let mut dirty = false;
-    let result = match x {
-        ExprAST::Int(_) | ExprAST::Bool(_) | ExprAST::StringLiteral(_)=> { x },
-        ExprAST::Variable(_) => {
-            // dirty = true;
-            solve(global, local, &x)
-        }
-        ExprAST::Block(_) => {todo!()}
-        ExprAST::If(_) => {todo!()}
-        ExprAST::CallCallableObjectByname(func_name, params) => {
-            let rp = solve_parameters(global, local, &params);
-            ExprAST::CallCallableObjectByname(func_name.to_owned(), box_expr(&rp))
-        }
-        ExprAST::Error => {todo!()}
-        ExprAST::CallBuiltinFunction(func_name, params) => {
-            let rp = solve_parameters(global, local, &params);
-            ExprAST::CallBuiltinFunction(func_name.to_owned(), box_expr(&rp))
-        }
-        ExprAST::Callable(co) => {ExprAST::Callable(co.clone())}
-        ExprAST::List(_) => { x }
-        ExprAST::Closure(_) => { todo!() }
-    };
 
-    // if dirty {
-    //     info!("Local env updated for {}", v);
-    //     local.insert(String::from(v), result.clone());
-    // }
-
-    // info!("Lazy lookup ({}) -> -> is {:?}", v, result);
-    result
+pub enum IroncamelFileInfo {
+    FileRead(Box<dyn BufRead>),
+    FileWrite(Box<dyn Write>),
+    Stdin(Box<dyn BufRead>),
+    Stdout(Box<dyn Write>),
 }
 
-fn solve_parameters(global: &GlobalState,
-                    local: &HashMap<String, ExprAST>,
-                    params: &Vec<Box<ExprAST>>)
-                    -> Vec<ExprAST>{
-    let mut solved = Vec::with_capacity(params.len());
-    for p in params {
-        let rp = solve(global, local, p);
-        solved.push(rp);
-    }
-    solved
-}
 
+// I think using enum in rust is better than using Java-like interfaces
+// At interpreter level, everything is almost expr
 
-fn process_global_functions(prog: &ProgramAST) -> HashMap<String,FunctionAST> {
-    let mut result = HashMap::new();
 
-    for func in &prog.functions {
-        let name = func.function_name.to_owned();
-        if name == "main" {
-            continue;
-            // We don't process main function here
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::{eval_with_options, eval_with_stdio_and_options, EvalOptions, Limit, Limits, StackFrame};
+    use crate::parser::build_ast;
+    use crate::pipeline::test_support;
+    use crate::tokenizer::convert_source_to_tokens;
+
+    #[test]
+    fn deep_stack_trace_is_cut() {
+        let code = "fn f(n) { +(1, f(-(n, 1))) } fn main() { f(100) }";
+        let ast = build_ast(&convert_source_to_tokens(code));
+        let options = EvalOptions { limits: Limits { max_depth: 50, ..Limits::default() }, ..EvalOptions::default() };
+        let error = match eval_with_options(&ast, &options) {
+            Err(e) => e,
+            Ok(_) => panic!("Expect a recursion limit error")
+        };
+        assert!(error.message.starts_with("recursion limit exceeded"));
+        // 20 innermost calls, the 25 omitted, 5 outermost calls and main
+        assert_eq!(error.stack.len(), 27);
+        assert!(matches!(error.stack[20], StackFrame::Omitted(25)));
+        assert!(matches!(&error.stack[26], StackFrame::Entry { name, .. } if name == "main"));
+        match &error.stack[25] {
+            StackFrame::Call { function, arguments, .. } => assert_eq!((function.as_str(), &arguments[..]), ("f", &[String::from("100")][..])),
+            _ => panic!("Expect the outermost call")
         }
-        debug!("interpreting {}", &name);
-        result.insert(name, func.clone());
     }
 
-    result
-}
+    #[test]
+    fn stack_trace_shows_call_sites() {
+        let code = "fn check(x) {
+                hd(x)
+            }
+            fn twice(f, x) { f(f(x)) }
+            fn main() {
+                let g = |l| { check(tl(l)) };
+                twice(g,
+                    list(1))
+            }";
+        let ast = test_support::build(code);
+        for vm in [false, true] {
+            let error = eval_with_options(&ast, &EvalOptions { vm, ..EvalOptions::default() }).unwrap_err();
+            let frames: Vec<(&str, &str)> = error.stack.iter().map(|frame| match frame {
+                StackFrame::Call { function, location, .. } => (function.as_str(), location.as_str()),
+                StackFrame::Entry { name, location } => (name.as_str(), location.as_str()),
+                StackFrame::Omitted(_) => panic!("Expect every call")
+            }).collect();
+            // The tail call to check replaced the closure, the builtin has its call site too
+            assert_eq!(frames, vec![("hd", "main:2"), ("check", "main:6"), ("twice", "main:7"), ("main", "main:5")]);
+        }
+    }
 
+    #[test]
+    fn limits_stop_the_program() {
+        let cases = [
+            ("fn f(n) { f(+(n, 1)) } fn main() { f(0) }", Limits { max_steps: Some(1000), ..Limits::default() }, Limit::Steps),
+            ("fn f(l) { f(cons(1, l)) } fn main() { f(list()) }", Limits { max_memory: Some(100), ..Limits::default() }, Limit::Memory),
+            ("fn main() { cons(1, list(2, 3)) }", Limits { max_list_length: Some(2), ..Limits::default() }, Limit::ListLength),
+            // Builtins are steps too
+            ("fn main() { +(1, +(2, +(3, 4))) }", Limits { max_steps: Some(2), ..Limits::default() }, Limit::Steps),
+        ];
+        for (code, limits, limit) in cases {
+            let ast = build_ast(&convert_source_to_tokens(code));
+            for vm in [false, true] {
+                let options = EvalOptions { limits: limits.clone(), vm, ..EvalOptions::default() };
+                match eval_with_stdio_and_options(&ast, Box::new(std::io::empty()), Box::new(std::io::sink()), &options) {
+                    Err(e) => assert_eq!(e.limit, Some(limit), "{}: {}", code, e),
+                    Ok(v) => panic!("{}: expect {:?} to be exceeded, got {}", code, limit, v)
+                }
+            }
+        }
+    }
 
-pub enum IroncamelFileInfo {
-    FileRead(BufReader<std::fs::File>),
-    FileWrite(std::fs::File),
-    Stdin,
-    Stdout,
+    #[test]
+    fn pow_is_bounded() {
+        let too_large = build_ast(&convert_source_to_tokens("fn main() { pow(2, 4000000000) }"));
+        let fine = build_ast(&convert_source_to_tokens("fn main() { +(pow(-(0, 1), 4000000001), str_len(itoa(pow(3, 100000)))) }"));
+        for vm in [false, true] {
+            let options = EvalOptions { vm, ..EvalOptions::default() };
+            match eval_with_options(&too_large, &options) {
+                Err(e) => assert!(e.message.starts_with("result too large: pow(2, 4000000000)"), "{}", e),
+                Ok(v) => panic!("Expect pow to fail, got {}", v)
+            }
+            assert_eq!(eval_with_options(&fine, &options).unwrap().to_string(), "47712");
+        }
+    }
 }
-
-
-// I think using enum in rust is better than using Java-like interfaces
-// At interpreter level, everything is almost expr
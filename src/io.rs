@@ -0,0 +1,203 @@
+// Where the IO statements of a program go. `readstr@stdin`, `writeline@stdout` and the streams opened by
+// `fopen_read`/`fopen_write` all come from an IoBackend, so a host can run a program against virtual files
+// with a scripted stdin and look at what it printed, without touching the disk.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Write};
+use std::rc::Rc;
+
+pub trait IoBackend {
+    // Both are called once for each run of the program, when its global state is built
+    fn stdin(&mut self) -> Box<dyn BufRead>;
+    fn stdout(&mut self) -> Box<dyn Write>;
+    fn open_read(&mut self, path: &str) -> std::io::Result<Box<dyn BufRead>>;
+    // Creates the file, or truncates it
+    fn open_write(&mut self, path: &str) -> std::io::Result<Box<dyn Write>>;
+    // None if it's not set, or not unicode
+    fn env_var(&mut self, name: &str) -> Option<String>;
+}
+
+// The real filesystem, with the stdin and stdout of the process unless others are given
+#[derive(Default)]
+pub struct FileSystem {
+    stdin: Option<Box<dyn BufRead>>,
+    stdout: Option<Box<dyn Write>>,
+}
+
+impl FileSystem {
+    pub fn new() -> FileSystem {
+        FileSystem::default()
+    }
+
+    pub fn with_stdio(stdin: Box<dyn BufRead>, stdout: Box<dyn Write>) -> FileSystem {
+        FileSystem { stdin: Some(stdin), stdout: Some(stdout) }
+    }
+}
+
+impl IoBackend for FileSystem {
+    fn stdin(&mut self) -> Box<dyn BufRead> {
+        self.stdin.take().unwrap_or_else(|| Box::new(BufReader::new(std::io::stdin())))
+    }
+
+    fn stdout(&mut self) -> Box<dyn Write> {
+        self.stdout.take().unwrap_or_else(|| Box::new(std::io::stdout()))
+    }
+
+    fn open_read(&mut self, path: &str) -> std::io::Result<Box<dyn BufRead>> {
+        Ok(Box::new(BufReader::new(std::fs::File::open(path)?)))
+    }
+
+    fn open_write(&mut self, path: &str) -> std::io::Result<Box<dyn Write>> {
+        Ok(Box::new(std::fs::File::create(path)?))
+    }
+
+    fn env_var(&mut self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+}
+
+type SharedBuffer = Rc<RefCell<Vec<u8>>>;
+
+// Everything in memory. A clone shares the files and the captured stdout, so the host keeps one
+// to look at them after giving the other to the interpreter
+#[derive(Clone, Default)]
+pub struct MemoryIo {
+    stdin: Rc<RefCell<Vec<u8>>>,
+    stdout: SharedBuffer,
+    files: Rc<RefCell<HashMap<String, SharedBuffer>>>,
+    env: Rc<RefCell<HashMap<String, String>>>,
+}
+
+impl MemoryIo {
+    pub fn new() -> MemoryIo {
+        MemoryIo::default()
+    }
+
+    // Every run of the program reads it from the start
+    pub fn with_stdin(self, input: &str) -> MemoryIo {
+        *self.stdin.borrow_mut() = input.as_bytes().to_vec();
+        self
+    }
+
+    pub fn with_file(self, path: &str, contents: &str) -> MemoryIo {
+        self.set_file(path, contents);
+        self
+    }
+
+    pub fn with_env(self, name: &str, value: &str) -> MemoryIo {
+        self.env.borrow_mut().insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn set_file(&self, path: &str, contents: &str) {
+        let buffer = Rc::new(RefCell::new(contents.as_bytes().to_vec()));
+        self.files.borrow_mut().insert(path.to_owned(), buffer);
+    }
+
+    // None if the program never created it
+    pub fn file(&self, path: &str) -> Option<String> {
+        self.files.borrow().get(path).map(|buffer| String::from_utf8_lossy(&buffer.borrow()).into_owned())
+    }
+
+    // Everything written to stdout so far
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.stdout.borrow()).into_owned()
+    }
+}
+
+impl IoBackend for MemoryIo {
+    fn stdin(&mut self) -> Box<dyn BufRead> {
+        Box::new(Cursor::new(self.stdin.borrow().clone()))
+    }
+
+    fn stdout(&mut self) -> Box<dyn Write> {
+        Box::new(SharedWriter(self.stdout.clone()))
+    }
+
+    // A snapshot of the file, writing to it at the same time is not seen
+    fn open_read(&mut self, path: &str) -> std::io::Result<Box<dyn BufRead>> {
+        match self.files.borrow().get(path) {
+            Some(buffer) => Ok(Box::new(Cursor::new(buffer.borrow().clone()))),
+            None => Err(std::io::Error::new(ErrorKind::NotFound, "No such file in memory"))
+        }
+    }
+
+    fn open_write(&mut self, path: &str) -> std::io::Result<Box<dyn Write>> {
+        let buffer = SharedBuffer::default();
+        self.files.borrow_mut().insert(path.to_owned(), buffer.clone());
+        Ok(Box::new(SharedWriter(buffer)))
+    }
+
+    fn env_var(&mut self, name: &str) -> Option<String> {
+        self.env.borrow().get(name).cloned()
+    }
+}
+
+struct SharedWriter(SharedBuffer);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::{eval_with_io, EvalOptions};
+    use crate::io::MemoryIo;
+    use crate::pipeline::test_support::{build, build_with_libraries};
+
+    #[test]
+    fn scripted_stdin_and_captured_stdout() {
+        let main = std::fs::read_to_string("example/io_read_then_sort.icml").unwrap();
+        let ast = build_with_libraries(&["include/core.icml", "include/stdlib.icml"], &main);
+        let input = std::fs::read_to_string("example/io_read_then_sort.stdin").unwrap();
+        let expected = std::fs::read_to_string("example/io_read_then_sort.expected").unwrap();
+        for options in [EvalOptions::default(), EvalOptions { vm: true, ..EvalOptions::default() }] {
+            let io = MemoryIo::new().with_stdin(&input);
+            eval_with_io(&ast, Box::new(io.clone()), &options).unwrap();
+            assert_eq!(format!("--- stdout\n{}", io.stdout()), expected);
+        }
+    }
+
+    #[test]
+    fn environment_and_arguments() {
+        let ast = build("fn main(args) {
+            env@process >> HOME;
+            env@process >> UNSET;
+            writelist@stdout << cons(HOME, cons(UNSET, args));
+            0 }");
+        for vm in [false, true] {
+            let options = EvalOptions { args: vec![String::from("-v")], vm, ..EvalOptions::default() };
+            let io = MemoryIo::new().with_env("HOME", "/home/student");
+            eval_with_io(&ast, Box::new(io.clone()), &options).unwrap();
+            assert_eq!(io.stdout(), "/home/student  -v \n");
+        }
+    }
+
+    #[test]
+    fn virtual_files() {
+        let ast = build("fn main() {
+            fopen_read@fin = \"numbers\";
+            readstr@fin >> s;
+            let l = strtok(s, \" \");
+            fopen_write@fout = \"out/sum\";
+            writeline@fout << +(atoi(hd(l)), atoi(hd(tl(l))));
+            writeline@stdout << \"done\";
+            0 }");
+        let io = MemoryIo::new().with_file("numbers", "40 2");
+        eval_with_io(&ast, Box::new(io.clone()), &EvalOptions::default()).unwrap();
+        assert_eq!(io.file("out/sum").as_deref(), Some("42\n"));
+        assert_eq!(io.stdout(), "done\n");
+
+        let ast = build("fn main() { fopen_read@fin = \"missing\"; 0 }");
+        let e = eval_with_io(&ast, Box::new(MemoryIo::new()), &EvalOptions::default()).unwrap_err();
+        assert!(e.message.starts_with("Can't open missing"), "{}", e.message);
+    }
+}
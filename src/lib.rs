@@ -4,7 +4,20 @@ pub mod tokenizer;
 pub mod parser;
 pub mod expr;
 pub mod pipeline;
+pub mod resolve;
+pub mod optimize;
+pub mod lint;
+pub mod lower;
 pub mod interpreter;
+pub mod bytecode;
+pub mod vm;
+pub mod artifact;
+pub mod native;
+pub mod embed;
+pub mod io;
+pub mod trace;
+pub mod env;
+pub mod value;
 pub mod debug_output;
 mod builtin;
 pub mod gen_ir;
@@ -0,0 +1,345 @@
+// Warnings for code which can't affect the result of the program.
+// Every expression is pure, so an unused let binding is pure waste, and so is a function which can't be reached.
+//
+// Names starting with an underscore are never reported as unused.
+// Functions from the included libraries (the prelude) are never reported as unreachable.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+use crate::expr::{cannot_fail, ClosureAST, ExprAST, IfElseExpr};
+use crate::parser::{BlockAST, FunctionAST, ProgramAST, StatementAST, TestAST};
+
+pub struct LintWarning {
+    pub location: String,
+    pub message: String
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+#[derive(PartialEq)]
+enum BindingKind {
+    Let,
+    Parameter,
+    // The result of IO, reading it is not a waste
+    Read
+}
+
+struct Binding {
+    name: String,
+    kind: BindingKind,
+    used: bool
+}
+
+struct UsageChecker {
+    bindings: Vec<Binding>,
+    location: String,
+    warnings: Vec<LintWarning>
+}
+
+// `libraries` are the paths of the included files
+pub fn lint_program(ast: &ProgramAST, libraries: &HashSet<String>) -> Vec<LintWarning> {
+    let mut checker = UsageChecker { bindings: Vec::new(), location: String::new(), warnings: Vec::new() };
+    for fun in &ast.functions {
+        checker.location = format!("fn {}", fun.function_name);
+        for arg in &fun.arguments {
+            checker.bind(arg, BindingKind::Parameter);
+        }
+        checker.visit_block(&fun.body.statements, &fun.body.return_expr);
+        checker.leave_scope(0);
+    }
+    for test in &ast.tests {
+        checker.location = format!("test {:?}", test.name);
+        checker.visit_block(&test.block.statements, &test.block.return_expr);
+    }
+
+    let mut warnings = checker.warnings;
+    if let Some(reachable) = reachable_functions(ast) {
+        for fun in &ast.functions {
+            if reachable.contains(fun.function_name.as_str()) || libraries.contains(&fun.origin) {
+                continue;
+            }
+            warnings.push(LintWarning {
+                location: format!("fn {}", fun.function_name),
+                message: String::from("function is never used")
+            });
+        }
+    }
+    warnings
+}
+
+impl UsageChecker {
+    fn bind(&mut self, name: &str, kind: BindingKind) {
+        self.bindings.push(Binding { name: name.to_owned(), kind, used: false });
+    }
+
+    fn use_name(&mut self, name: &str) {
+        if let Some(b) = self.bindings.iter_mut().rev().find(|b| b.name == name) {
+            b.used = true;
+        }
+    }
+
+    // Drop every binding since `mark`, reporting the unused ones
+    fn leave_scope(&mut self, mark: usize) {
+        for b in self.bindings.drain(mark..) {
+            if b.used || b.name.starts_with('_') {
+                continue;
+            }
+            let message = match b.kind {
+                BindingKind::Let => format!("unused let binding `{}`", b.name),
+                BindingKind::Parameter => format!("unused parameter `{}`", b.name),
+                BindingKind::Read => continue
+            };
+            self.warnings.push(LintWarning { location: self.location.to_owned(), message });
+        }
+    }
+
+    fn visit_block(&mut self, statements: &Vec<StatementAST>, return_expr: &ExprAST) {
+        let mark = self.bindings.len();
+        for s in statements {
+            match s {
+                StatementAST::Bind(lb) => {
+                    self.visit_expr(&lb.expr);
+                    self.bind(&lb.variable, BindingKind::Let);
+                },
+                StatementAST::Read(r) => self.bind(&r.write_to_variable, BindingKind::Read),
+                StatementAST::Write(w) => self.visit_expr(&w.expr),
+                StatementAST::FileOpen(_) | StatementAST::Error => ()
+            }
+        }
+        self.visit_expr(return_expr);
+        self.leave_scope(mark);
+    }
+
+    fn visit_expr(&mut self, expr: &ExprAST) {
+        match expr {
+            ExprAST::Variable(v) => self.use_name(v),
+            ExprAST::CallCallableObjectByname(func_name, params, _) => {
+                self.use_name(func_name);
+                for p in params {
+                    self.visit_expr(p);
+                }
+            },
+            ExprAST::If(if_expr) => {
+                self.visit_expr(&if_expr.condition);
+                self.visit_block(&if_expr.then_case.statements, &if_expr.then_case.return_expr);
+                self.visit_block(&if_expr.else_case.statements, &if_expr.else_case.return_expr);
+            },
+            ExprAST::Block(block) => self.visit_block(&block.statements, &block.return_expr),
+            ExprAST::Closure(clos) => {
+                let mark = self.bindings.len();
+                for p in &clos.params {
+                    self.bind(p, BindingKind::Parameter);
+                }
+                self.visit_block(&clos.block.statements, &clos.block.return_expr);
+                self.leave_scope(mark);
+            },
+            _ => ()
+        }
+    }
+}
+
+// Every identifier mentioned in the expression, bound inside it or not
+fn collect_names(expr: &ExprAST, names: &mut HashSet<String>) {
+    match expr {
+        ExprAST::Variable(v) => { names.insert(v.to_owned()); },
+        ExprAST::CallCallableObjectByname(func_name, params, _) => {
+            names.insert(func_name.to_owned());
+            for p in params {
+                collect_names(p, names);
+            }
+        },
+        ExprAST::If(if_expr) => {
+            collect_names(&if_expr.condition, names);
+            collect_block_names(&if_expr.then_case, names);
+            collect_block_names(&if_expr.else_case, names);
+        },
+        ExprAST::Block(block) => collect_block_names(block, names),
+        ExprAST::Closure(clos) => collect_block_names(&clos.block, names),
+        _ => ()
+    }
+}
+
+fn collect_block_names(block: &BlockAST, names: &mut HashSet<String>) {
+    collect_statement_names(&block.statements, names);
+    collect_names(&block.return_expr, names);
+}
+
+fn collect_statement_names(statements: &[StatementAST], names: &mut HashSet<String>) {
+    for s in statements {
+        match s {
+            StatementAST::Bind(lb) => collect_names(&lb.expr, names),
+            StatementAST::Write(w) => collect_names(&w.expr, names),
+            _ => ()
+        }
+    }
+}
+
+// Functions reachable from main and the tests. None if there is no such entry point
+fn reachable_functions(ast: &ProgramAST) -> Option<HashSet<&str>> {
+    let mut pending: Vec<&FunctionAST> = Vec::new();
+    let mut reachable: HashSet<&str> = HashSet::new();
+    let mut names = HashSet::new();
+    for fun in &ast.functions {
+        if fun.function_name == "main" {
+            reachable.insert(&fun.function_name);
+            pending.push(fun);
+        }
+    }
+    for test in &ast.tests {
+        collect_block_names(&test.block, &mut names);
+    }
+    if pending.is_empty() && ast.tests.is_empty() {
+        return None;
+    }
+    loop {
+        for fun in pending.drain(..) {
+            collect_block_names(&fun.body, &mut names);
+        }
+        // Local names can't shadow a global function, so every mention of a function's name is a use
+        for fun in &ast.functions {
+            if names.contains(&fun.function_name) && reachable.insert(&fun.function_name) {
+                pending.push(fun);
+            }
+        }
+        names.clear();
+        if pending.is_empty() {
+            break;
+        }
+    }
+    Some(reachable)
+}
+
+// Remove unused let bindings and unreachable functions, prelude functions included.
+// Only a binding which can't fail goes, an unused `let _ = assert_eq(...)` still fails its test.
+// Removing a binding may make another one unused, so a block is scanned backwards.
+pub fn eliminate_dead_code(ast: ProgramAST) -> ProgramAST {
+    let functions: Vec<FunctionAST> = ast.functions.into_iter().map(|fun| FunctionAST {
+        body: eliminate_in_nested_block(fun.body),
+        ..fun
+    }).collect();
+    let tests: Vec<TestAST> = ast.tests.into_iter().map(|t| TestAST {
+        block: eliminate_in_nested_block(t.block),
+        ..t
+    }).collect();
+    let program = ProgramAST { functions, tests };
+
+    let reachable: HashSet<String> = match reachable_functions(&program) {
+        Some(r) => r.into_iter().map(String::from).collect(),
+        None => return program
+    };
+    let functions = program.functions.into_iter()
+        .filter(|f| reachable.contains(&f.function_name))
+        .collect();
+    ProgramAST { functions, tests: program.tests }
+}
+
+fn eliminate_in_block(statements: Vec<StatementAST>, return_expr: ExprAST) -> (Vec<StatementAST>, ExprAST) {
+    let return_expr = eliminate_in_expr(return_expr);
+    let mut live = HashSet::new();
+    collect_names(&return_expr, &mut live);
+
+    let mut kept = Vec::with_capacity(statements.len());
+    for s in statements.into_iter().rev() {
+        let s = match s {
+            StatementAST::Bind(lb) if !live.contains(&lb.variable) && cannot_fail(&lb.expr) => continue,
+            StatementAST::Bind(mut lb) => {
+                lb.expr = Box::new(eliminate_in_expr(*lb.expr));
+                StatementAST::Bind(lb)
+            },
+            StatementAST::Write(mut w) => {
+                w.expr = Box::new(eliminate_in_expr(*w.expr));
+                StatementAST::Write(w)
+            },
+            _ => s
+        };
+        collect_statement_names(std::slice::from_ref(&s), &mut live);
+        kept.push(s);
+    }
+    kept.reverse();
+    (kept, return_expr)
+}
+
+// The block is only copied if it's shared
+fn eliminate_in_nested_block(block: Rc<BlockAST>) -> Rc<BlockAST> {
+    let block = Rc::unwrap_or_clone(block);
+    let (statements, return_expr) = eliminate_in_block(block.statements, *block.return_expr);
+    Rc::new(BlockAST { statements, return_expr: Box::new(return_expr) })
+}
+
+fn eliminate_in_expr(expr: ExprAST) -> ExprAST {
+    match expr {
+        ExprAST::CallCallableObjectByname(func_name, params, line) => {
+            let params = params.into_iter().map(|p| Box::new(eliminate_in_expr(*p))).collect();
+            ExprAST::CallCallableObjectByname(func_name, params, line)
+        },
+        ExprAST::If(if_expr) => ExprAST::If(IfElseExpr {
+            condition: Box::new(eliminate_in_expr(*if_expr.condition)),
+            then_case: eliminate_in_nested_block(if_expr.then_case),
+            else_case: eliminate_in_nested_block(if_expr.else_case)
+        }),
+        ExprAST::Block(block) => ExprAST::Block(eliminate_in_nested_block(block)),
+        ExprAST::Closure(clos) => {
+            let clos = Rc::unwrap_or_clone(clos);
+            let block = eliminate_in_nested_block(clos.block);
+            ExprAST::Closure(Rc::new(ClosureAST { params: clos.params, block }))
+        },
+        _ => expr
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use crate::interpreter::{eval, run_tests, EvalOptions};
+    use crate::lint::{eliminate_dead_code, lint_program};
+    use crate::parser::{build_ast, ProgramAST};
+    use crate::tokenizer::convert_source_to_tokens;
+
+    fn parse(code: &str) -> ProgramAST {
+        build_ast(&convert_source_to_tokens(code))
+    }
+
+    #[test]
+    fn report_unused() {
+        let code = "fn f(a, b, _c) { let x = 1; let y = |z| { a }; y(2) }
+            fn dead() { 0 }
+            fn main() { readstr@stdin >> s; let u = f(1, 2, 3); 0 }";
+        let warnings: Vec<String> = lint_program(&parse(code), &HashSet::new())
+            .iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec![
+            "fn f: unused parameter `z`",
+            "fn f: unused let binding `x`",
+            "fn f: unused parameter `b`",
+            "fn main: unused let binding `u`",
+            "fn dead: function is never used",
+        ]);
+    }
+
+    #[test]
+    fn eliminate_transitively() {
+        let code = "fn g(a) { a }
+            fn dead() { 0 }
+            fn main() { let a = |x| { g(x) }; let b = a; let c = 2; writeline@stdout << c; 0 }";
+        let ast = eliminate_dead_code(parse(code));
+        let names: Vec<&str> = ast.functions.iter().map(|f| f.function_name.as_str()).collect();
+        assert_eq!(names, vec!["main"]);
+        assert_eq!(ast.functions[0].body.statements.len(), 2);
+    }
+
+    #[test]
+    fn keep_bindings_which_fail() {
+        let code = "fn main() { let unused = hd(list()); 0 }
+            test \"unused assert\" { let _ = assert_eq(1, 2); true }";
+        let ast = eliminate_dead_code(parse(code));
+        assert_eq!(ast.functions[0].body.statements.len(), 1);
+        assert!(eval(&ast).is_err());
+        let outcomes = run_tests(&ast, &EvalOptions::default());
+        assert!(outcomes[0].failure.as_ref().is_some_and(|f| f.starts_with("assert_eq failed: 1 != 2")));
+    }
+}
@@ -0,0 +1,413 @@
+// Resolve every identifier before execution, so the interpreter never looks a name up.
+//   a local variable     -> (depth, slot), the `slot`-th value of the frame `depth` levels up (see env.rs)
+//   a global function    -> its FunctionId, an index into LoweredProgram.functions
+//   a builtin            -> its name, a &'static str, also for the natives registered by the host
+//   a closure expression -> its ClosureId, an index into LoweredProgram.closures
+//   a call               -> its SiteId, an index into LoweredProgram.sites, where it is for stack traces
+// A function or closure body always runs in a new frame, a nested block only if it has let bindings.
+//
+// A variable and a callee are looked up in the same order: local, builtin, global function.
+// So a parameter or let binding named like a builtin, `max` or `lower`, shadows it in its scope.
+// check_program has rejected unknown names before, so they only panic here if it was skipped.
+//
+// The special forms become if expressions, the right operand is evaluated only when it decides the result
+// and is returned as it is, so it's still a tail call:
+//   &&(a, b) -> if a then { b } else { false }
+//   ||(a, b) -> if a then { true } else { b }
+//   !(a)     -> if a then { false } else { true }
+//
+// In the lazy mode, let bindings and the arguments of calls which don't need their value right away
+// (functions, closures, `cons` and `list`) are delayed: a ThunkId, an index into LoweredProgram.thunks,
+// evaluated in the environment where it was created at most once, when something needs its value.
+
+use std::collections::HashMap;
+use crate::builtin;
+use crate::expr::ExprAST;
+use crate::native::Natives;
+use crate::parser::{BlockAST, FileOpenAst, ProgramAST, StatementAST};
+use crate::value::Value;
+
+pub type FunctionId = usize;
+pub type ClosureId = usize;
+pub type ThunkId = usize;
+pub type SiteId = usize;
+
+pub enum Code {
+    // Literals
+    Const(Value),
+    Local { depth: usize, slot: usize },
+    // A global function or a builtin used as a value
+    Function(FunctionId),
+    Builtin(&'static str),
+    Call(Callee, Vec<Code>, SiteId),
+    If(Box<Code>, Box<BlockCode>, Box<BlockCode>),
+    Block(Box<BlockCode>),
+    Closure(ClosureId),
+    // A thunk of the lazy mode
+    Delay(ThunkId),
+}
+
+pub enum Callee {
+    Local { depth: usize, slot: usize },
+    Function(FunctionId),
+    Builtin(&'static str),
+}
+
+pub enum StatementCode {
+    // Let and Read bind the next slot of the frame
+    Let(Code),
+    // `variable` is the name bound, which env@process reads
+    Read { procedure: String, file_handler: String, variable: String },
+    Write { procedure: String, file_handler: String, code: Code },
+    FileOpen(FileOpenAst),
+}
+
+pub struct BlockCode {
+    pub statements: Vec<StatementCode>,
+    pub return_code: Code
+}
+
+// Functions, closures, main and tests
+pub struct FunctionCode {
+    // Such as `fold`, `closure in fold` or `test "reverse"`
+    pub name: String,
+    // Where it is defined, like `include/core.icml:12`, shown in stack traces for main and tests.
+    // A closure has the location of the function defining it
+    pub location: String,
+    pub arity: usize,
+    pub body: BlockCode
+}
+
+pub struct LoweredProgram {
+    pub functions: Vec<FunctionCode>,
+    pub closures: Vec<FunctionCode>,
+    pub function_ids: HashMap<String, FunctionId>,
+    pub main: Option<FunctionCode>,
+    pub tests: Vec<FunctionCode>,
+    // Always empty unless lowered lazily
+    pub thunks: Vec<Code>,
+    // The location of every call, like `include/core.icml:14`, empty if unknown
+    pub sites: Vec<String>
+}
+
+struct Lowerer {
+    function_ids: HashMap<String, FunctionId>,
+    closures: Vec<FunctionCode>,
+    thunks: Vec<Code>,
+    lazy: bool,
+    natives: Natives,
+    // The names bound in each frame in slot order, innermost last
+    frames: Vec<Vec<String>>,
+    sites: Vec<String>,
+    // The function being lowered, for the closures it defines and the calls in it
+    name: String,
+    origin: String,
+    location: String
+}
+
+pub fn lower_program(ast: &ProgramAST) -> LoweredProgram {
+    lower_program_with_natives(ast, &Natives::default(), false)
+}
+
+// For the lazy mode
+pub fn lower_program_lazily(ast: &ProgramAST) -> LoweredProgram {
+    lower_program_with_natives(ast, &Natives::default(), true)
+}
+
+pub fn lower_program_with_natives(ast: &ProgramAST, natives: &Natives, lazy: bool) -> LoweredProgram {
+    let mut function_ids = HashMap::new();
+    for fun in ast.functions.iter().filter(|f| f.function_name != "main") {
+        let id = function_ids.len();
+        function_ids.insert(fun.function_name.to_owned(), id);
+    }
+    let mut lowerer = Lowerer {
+        function_ids, closures: Vec::new(), thunks: Vec::new(), lazy, natives: natives.clone(),
+        sites: Vec::new(), frames: Vec::new(), name: String::new(), origin: String::new(), location: String::new()
+    };
+
+    let mut functions = Vec::with_capacity(lowerer.function_ids.len());
+    let mut main = None;
+    for fun in &ast.functions {
+        lowerer.name = fun.function_name.to_owned();
+        lowerer.origin = fun.origin.to_owned();
+        lowerer.location = format_location(&fun.origin, fun.line);
+        let body = lowerer.lower_body(&fun.arguments, &fun.body.statements, &fun.body.return_expr);
+        let code = FunctionCode {
+            name: fun.function_name.to_owned(),
+            location: lowerer.location.to_owned(),
+            arity: fun.arguments.len(),
+            body
+        };
+        if fun.function_name == "main" {
+            main = Some(code);
+        } else {
+            functions.push(code);
+        }
+    }
+    let mut tests = Vec::with_capacity(ast.tests.len());
+    for test in &ast.tests {
+        lowerer.name = format!("test {:?}", test.name);
+        lowerer.origin = test.origin.to_owned();
+        lowerer.location = format_location(&test.origin, test.line);
+        let body = lowerer.lower_body(&[], &test.block.statements, &test.block.return_expr);
+        tests.push(FunctionCode { name: test.name.to_owned(), location: lowerer.location.to_owned(), arity: 0, body });
+    }
+    LoweredProgram {
+        functions, closures: lowerer.closures, function_ids: lowerer.function_ids, main, tests, thunks: lowerer.thunks,
+        sites: lowerer.sites
+    }
+}
+
+// Empty if the AST wasn't built by the pipeline
+fn format_location(origin: &str, line: usize) -> String {
+    match (origin.is_empty(), line) {
+        (true, 0) => String::new(),
+        (true, _) => format!("line {}", line),
+        (false, 0) => origin.to_owned(),
+        (false, _) => format!("{}:{}", origin, line)
+    }
+}
+
+fn block_of(code: Code) -> Box<BlockCode> {
+    Box::new(BlockCode { statements: Vec::new(), return_code: code })
+}
+
+impl Lowerer {
+    // A new frame starting with the parameters
+    fn lower_body(&mut self, params: &[String], statements: &[StatementAST], return_expr: &ExprAST) -> BlockCode {
+        self.frames.push(params.to_vec());
+        let block = self.lower_statements(statements, return_expr);
+        self.frames.pop();
+        block
+    }
+
+    fn lower_nested_block(&mut self, block: &BlockAST) -> BlockCode {
+        if block.statements.is_empty() {
+            return BlockCode { statements: Vec::new(), return_code: self.lower_expr(&block.return_expr) };
+        }
+        self.lower_body(&[], &block.statements, &block.return_expr)
+    }
+
+    fn bind(&mut self, name: &str) {
+        self.frames.last_mut().unwrap().push(name.to_owned());
+    }
+
+    fn lower_statements(&mut self, statements: &[StatementAST], return_expr: &ExprAST) -> BlockCode {
+        let mut lowered = Vec::with_capacity(statements.len());
+        for s in statements {
+            let code = match s {
+                StatementAST::Bind(lb) => {
+                    let code = self.lower_delayed(&lb.expr);
+                    self.bind(&lb.variable);
+                    StatementCode::Let(code)
+                },
+                StatementAST::Read(r) => {
+                    self.bind(&r.write_to_variable);
+                    StatementCode::Read {
+                        procedure: r.impure_procedure_name.to_owned(),
+                        file_handler: r.file_handler.to_owned(),
+                        variable: r.write_to_variable.to_owned()
+                    }
+                },
+                StatementAST::Write(w) => StatementCode::Write {
+                    procedure: w.impure_procedure_name.to_owned(),
+                    file_handler: w.file_handler.to_owned(),
+                    code: self.lower_expr(&w.expr)
+                },
+                StatementAST::FileOpen(fo) => StatementCode::FileOpen(fo.clone()),
+                StatementAST::Error => panic!("Not supported other statements!")
+            };
+            lowered.push(code);
+        }
+        BlockCode { statements: lowered, return_code: self.lower_expr(return_expr) }
+    }
+
+    // Newest binding first, a closure parameter hides a captured name
+    fn find_local(&self, name: &str) -> Option<(usize, usize)> {
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            if let Some(slot) = frame.iter().rposition(|n| n == name) {
+                return Some((depth, slot));
+            }
+        }
+        None
+    }
+
+    fn lower_expr(&mut self, expr: &ExprAST) -> Code {
+        match expr {
+            ExprAST::Int(x) => Code::Const(Value::Int(*x)),
+            ExprAST::BigInt(x) => Code::Const(Value::integer(x.clone())),
+            ExprAST::Bool(x) => Code::Const(Value::Bool(*x)),
+            ExprAST::StringLiteral(s) => Code::Const(Value::str(s)),
+            ExprAST::Variable(v) => {
+                if let Some((depth, slot)) = self.find_local(v) {
+                    Code::Local { depth, slot }
+                } else if let Some(name) = self.find_builtin(v) {
+                    Code::Builtin(name)
+                } else if let Some(id) = self.function_ids.get(v) {
+                    Code::Function(*id)
+                } else {
+                    panic!("Not found variable ({}) in local scope", v)
+                }
+            },
+            ExprAST::CallCallableObjectByname(func_name, params, _)
+                if builtin::LOGICAL_OPERATORS.contains(&func_name.as_str()) => self.lower_logical(func_name, params),
+            ExprAST::CallCallableObjectByname(func_name, params, line) => {
+                let callee = if let Some((depth, slot)) = self.find_local(func_name) {
+                    Callee::Local { depth, slot }
+                } else if let Some(name) = self.find_builtin(func_name) {
+                    Callee::Builtin(name)
+                } else if let Some(id) = self.function_ids.get(func_name) {
+                    Callee::Function(*id)
+                } else {
+                    panic!("Can't find a callable object called ({})", func_name)
+                };
+                let params = match callee {
+                    Callee::Builtin(name) if !builtin::is_lazy_builtin(name) => self.lower_params(params),
+                    _ => params.iter().map(|p| self.lower_delayed(p)).collect()
+                };
+                self.sites.push(format_location(&self.origin, *line));
+                Code::Call(callee, params, self.sites.len() - 1)
+            },
+            ExprAST::If(if_expr) => Code::If(
+                Box::new(self.lower_expr(&if_expr.condition)),
+                Box::new(self.lower_nested_block(&if_expr.then_case)),
+                Box::new(self.lower_nested_block(&if_expr.else_case))
+            ),
+            ExprAST::Block(block) => Code::Block(Box::new(self.lower_nested_block(block))),
+            ExprAST::Closure(clos) => {
+                let body = self.lower_body(&clos.params, &clos.block.statements, &clos.block.return_expr);
+                let name = format!("closure in {}", self.name);
+                let location = self.location.to_owned();
+                self.closures.push(FunctionCode { name, location, arity: clos.params.len(), body });
+                Code::Closure(self.closures.len() - 1)
+            },
+            ExprAST::Error => panic!("Error!")
+        }
+    }
+
+    fn find_builtin(&self, name: &str) -> Option<&'static str> {
+        builtin::find_builtin(name).or_else(|| self.natives.find(name).map(|n| n.name))
+    }
+
+    fn lower_logical(&mut self, op: &str, params: &[Box<ExprAST>]) -> Code {
+        let condition = Box::new(self.lower_expr(&params[0]));
+        let constant = |x| block_of(Code::Const(Value::Bool(x)));
+        match op {
+            "&&" => Code::If(condition, block_of(self.lower_expr(&params[1])), constant(false)),
+            "||" => Code::If(condition, constant(true), block_of(self.lower_expr(&params[1]))),
+            "!" => Code::If(condition, constant(false), constant(true)),
+            _ => panic!("Not a logical operator ({})", op)
+        }
+    }
+
+    fn lower_params(&mut self, params: &[Box<ExprAST>]) -> Vec<Code> {
+        params.iter().map(|p| self.lower_expr(p)).collect()
+    }
+
+    // A thunk in the lazy mode, unless there is nothing to evaluate
+    fn lower_delayed(&mut self, expr: &ExprAST) -> Code {
+        let code = self.lower_expr(expr);
+        match code {
+            Code::Const(_) | Code::Local { .. } | Code::Function(_) | Code::Builtin(_) | Code::Closure(_) => code,
+            _ if !self.lazy => code,
+            _ => {
+                self.thunks.push(code);
+                Code::Delay(self.thunks.len() - 1)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::lower::{Callee, Code, lower_program, lower_program_lazily, StatementCode};
+    use crate::parser::build_ast;
+    use crate::tokenizer::convert_source_to_tokens;
+    use crate::value::Value;
+
+    #[test]
+    fn resolve_slots() {
+        let code = "fn f(a, b) { let c = +(a, b); let g = |x| { if true then { let y = x; +(y, c) } else { b } }; g(1) }";
+        let program = lower_program(&build_ast(&convert_source_to_tokens(code)));
+        let f = &program.functions[program.function_ids["f"]];
+        match &f.body.statements[0] {
+            StatementCode::Let(Code::Call(Callee::Builtin("+"), params, _)) => {
+                assert!(matches!(params[..], [Code::Local { depth: 0, slot: 0 }, Code::Local { depth: 0, slot: 1 }]));
+            },
+            _ => panic!("Expect a call to +")
+        }
+        assert!(matches!(f.body.return_code, Code::Call(Callee::Local { depth: 0, slot: 3 }, ..)));
+
+        let g = &program.closures[0];
+        assert_eq!(g.name, "closure in f");
+        let (then_case, else_case) = match &g.body.return_code {
+            Code::If(_, then_case, else_case) => (then_case, else_case),
+            _ => panic!("Expect an if")
+        };
+        // The then branch has its own frame, the closure's frame is one level up and f's two levels up
+        assert!(matches!(then_case.statements[0], StatementCode::Let(Code::Local { depth: 1, slot: 0 })));
+        match &then_case.return_code {
+            Code::Call(_, params, _) => assert!(matches!(params[..],
+                [Code::Local { depth: 0, slot: 0 }, Code::Local { depth: 2, slot: 2 }])),
+            _ => panic!("Expect a call")
+        }
+        assert!(matches!(else_case.return_code, Code::Local { depth: 1, slot: 1 }));
+    }
+
+    #[test]
+    fn locals_shadow_builtins() {
+        let code = "fn f(max) { let abs = +(max, 1); max(abs) }";
+        let program = lower_program(&build_ast(&convert_source_to_tokens(code)));
+        let f = &program.functions[0];
+        match &f.body.statements[0] {
+            StatementCode::Let(Code::Call(Callee::Builtin("+"), params, _)) => {
+                assert!(matches!(params[..], [Code::Local { depth: 0, slot: 0 }, Code::Const(_)]));
+            },
+            _ => panic!("Expect a call to +")
+        }
+        assert!(matches!(&f.body.return_code, Code::Call(Callee::Local { depth: 0, slot: 0 }, params, _)
+            if matches!(params[..], [Code::Local { depth: 0, slot: 1 }])));
+    }
+
+    #[test]
+    fn logical_operators_are_ifs() {
+        let code = "fn f(a, b) { ||(!(a), &&(b, a)) }";
+        let program = lower_program(&build_ast(&convert_source_to_tokens(code)));
+        let (condition, then_case, else_case) = match &program.functions[0].body.return_code {
+            Code::If(condition, then_case, else_case) => (condition, then_case, else_case),
+            _ => panic!("Expect an if")
+        };
+        assert!(matches!(**condition, Code::If(_, _, _)));
+        assert!(matches!(then_case.return_code, Code::Const(Value::Bool(true))));
+        match &else_case.return_code {
+            Code::If(condition, then_case, else_case) => {
+                assert!(matches!(**condition, Code::Local { depth: 0, slot: 1 }));
+                assert!(matches!(then_case.return_code, Code::Local { depth: 0, slot: 0 }));
+                assert!(matches!(else_case.return_code, Code::Const(Value::Bool(false))));
+            },
+            _ => panic!("Expect an if for &&")
+        }
+    }
+
+    #[test]
+    fn delay_in_lazy_mode() {
+        let code = "fn f(a) { let b = +(a, 1); g(b, cons(+(b, 2), list()), 3) } fn g(x, y, z) { x }";
+        let ast = build_ast(&convert_source_to_tokens(code));
+        let program = lower_program_lazily(&ast);
+        let f = &program.functions[program.function_ids["f"]];
+        assert!(matches!(f.body.statements[0], StatementCode::Let(Code::Delay(0))));
+        // The params of + are needed right away, the ones of cons are not
+        assert!(matches!(&program.thunks[0], Code::Call(Callee::Builtin("+"), params, _)
+            if matches!(params[..], [Code::Local { .. }, Code::Const(_)])));
+        match &f.body.return_code {
+            Code::Call(Callee::Function(_), params, _) => {
+                assert!(matches!(params[..], [Code::Local { .. }, Code::Delay(3), Code::Const(_)]))
+            },
+            _ => panic!("Expect a call to g")
+        }
+        assert!(matches!(&program.thunks[3], Code::Call(Callee::Builtin("cons"), params, _)
+            if matches!(params[..], [Code::Delay(1), Code::Delay(2)])));
+        assert!(lower_program(&ast).thunks.is_empty());
+    }
+}
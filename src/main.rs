@@ -1,10 +1,21 @@
 use std::fs;
-use log::{debug, error, info};
+use log::{error, info};
 use ironcamel::pipeline;
+use ironcamel::pipeline::{PipelineOptions, SourceFile};
+use ironcamel::parser::ProgramAST;
+use ironcamel::interpreter::{EvalOptions, Limits, RuntimeError};
+use ironcamel::trace::IoTrace;
+use ironcamel::value::Value;
+use ironcamel::artifact::{self, Artifact, SourceDigest};
 use std::io::Write;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 
+// Problems found before execution and failed tests exit with 1
+const EXIT_RUNTIME_ERROR: i32 = 2;
+// A runtime error raised by --max-steps, --max-depth, --max-list-length or --max-memory
+const EXIT_LIMIT_EXCEEDED: i32 = 3;
+
 enum RunMode {
     AdHoc,
     CompileToLLVMIR,
@@ -13,7 +24,7 @@ enum RunMode {
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// The source file to be executed
+    /// The source file to be executed, or an artifact made by `build`
     #[clap(short, long)]
     run: Option<String>,
 
@@ -21,27 +32,245 @@ struct Args {
     compile: Option<String>,
 
     /// Libaraies to be included
-    #[clap(short, long)]
+    #[clap(short, long, global = true)]
     include: Vec<String>,
+
+    /// Remove unused let bindings and unreachable functions before evaluation
+    #[clap(long, global = true)]
+    eliminate_dead_code: bool,
+
+    /// Maximum number of nested non-tail calls
+    #[clap(long, global = true, default_value_t = ironcamel::interpreter::DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+
+    /// Maximum number of calls of functions, closures and builtins, unlimited by default
+    #[clap(long, global = true)]
+    max_steps: Option<u64>,
+
+    /// Maximum number of elements of a list built by a builtin, unlimited by default
+    #[clap(long, global = true)]
+    max_list_length: Option<usize>,
+
+    /// Maximum number of list nodes allocated during the run, unlimited by default
+    #[clap(long, global = true)]
+    max_memory: Option<u64>,
+
+    /// Evaluate let bindings and function arguments only when their values are needed
+    #[clap(long, global = true)]
+    lazy: bool,
+
+    /// Compile the program to bytecode and run it on a virtual machine
+    #[clap(long, global = true, conflicts_with = "lazy")]
+    vm: bool,
+
+    /// Write every IO operation of the run and its result to this file
+    #[clap(long, value_name = "FILE")]
+    record: Option<String>,
+
+    /// Run again against the IO recorded by --record, without touching the files or the stdin
+    #[clap(long, value_name = "FILE", conflicts_with = "record")]
+    replay: Option<String>,
+
+    /// Arguments after `--`, given to `fn main(args)` as a list of strings
+    #[clap(last = true, value_name = "ARGS")]
+    program_args: Vec<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run every `test "name" { ... }` block in the given files and the included libraries
+    Test {
+        /// Source files containing tests
+        files: Vec<String>,
+    },
+    /// Compile a program and the included libraries into an .icmlc artifact, which --run executes directly
+    Build {
+        /// The source file containing main
+        file: String,
+        /// Where the artifact is written, the source file with the .icmlc extension by default
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+}
+
+fn read_source_file(path: &String) -> SourceFile {
+    let code = fs::read_to_string(path)
+        .expect("Something went wrong reading the file");
+    SourceFile { path: path.to_owned(), code, is_library: false }
 }
 
-fn read_source_code(args: &Args)->(RunMode, String){
+fn read_source_code(args: &Args)->(RunMode, SourceFile){
     if (!args.run.is_none())  &&  (!args.compile.is_none()) {
         panic!("We can't define both --run and --compile");
     }
     if !args.run.is_none() {
-        let main_code = fs::read_to_string(args.run.as_ref().unwrap())
-            .expect("Something went wrong reading the file");
+        let main_code = read_source_file(args.run.as_ref().unwrap());
         return (RunMode::AdHoc, main_code);
     }
     if !args.compile.is_none() {
-        let main_code = fs::read_to_string(args.compile.as_ref().unwrap())
-            .expect("Something went wrong reading the file");
+        let main_code = read_source_file(args.compile.as_ref().unwrap());
         return (RunMode::CompileToLLVMIR, main_code);
     }
     panic!("No source code is provided");
 }
 
+fn read_libraries(include: &Vec<String>) -> Vec<SourceFile> {
+    let mut source_vec = Vec::with_capacity(include.len() + 1);
+    for lib_path in include {
+        match fs::read_to_string(&lib_path) {
+            Ok(s) => { source_vec.push(SourceFile { path: lib_path.to_owned(), code: s, is_library: true }); }
+            Err(e) => { error!("Read lib {} failed: {}, skipping\n", lib_path, e) }
+        }
+    }
+    source_vec
+}
+
+// Report every problem found before execution, then give up
+fn build_program(source_vec: Vec<SourceFile>, args: &Args) -> ProgramAST {
+    let options = PipelineOptions { eliminate_dead_code: args.eliminate_dead_code, ..PipelineOptions::default() };
+    match pipeline::build_program(&source_vec, &options) {
+        Ok(program) => {
+            for w in &program.warnings {
+                eprintln!("warning: {}", w);
+            }
+            program.ast
+        },
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("error: {}", e);
+            }
+            eprintln!("{} errors found, aborting", errors.len());
+            std::process::exit(1);
+        }
+    }
+}
+
+fn eval_options(args: &Args) -> EvalOptions {
+    let limits = Limits {
+        max_depth: args.max_depth,
+        max_steps: args.max_steps,
+        max_list_length: args.max_list_length,
+        max_memory: args.max_memory
+    };
+    EvalOptions { limits, lazy: args.lazy, vm: args.vm, trace: None, args: args.program_args.clone() }
+}
+
+// The integer returned by main is the exit status, any other value exits with 0.
+// The trace is saved even if the run fails, that's the run to reproduce
+fn run_main(args: &Args, run: impl FnOnce(&EvalOptions) -> Result<Value, RuntimeError>) {
+    let trace = match (&args.record, &args.replay) {
+        (Some(_), _) => Some(IoTrace::record()),
+        (_, Some(path)) => match fs::read_to_string(path).map_err(|e| e.to_string()).and_then(|log| IoTrace::from_log(&log)) {
+            Ok(trace) => Some(trace),
+            Err(e) => {
+                eprintln!("error: can't replay {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        _ => None
+    };
+    let options = EvalOptions { trace: trace.clone(), ..eval_options(args) };
+    let result = run(&options);
+    if let (Some(path), Some(trace)) = (&args.record, &trace)
+        && let Err(e) = fs::write(path, trace.to_log()) {
+        eprintln!("error: can't write {}: {}", path, e);
+        std::process::exit(1);
+    }
+    let value = result.unwrap_or_else(|e| exit_on_runtime_error(&e));
+    if let Some(Err(e)) = trace.map(|t| t.finish()) {
+        eprintln!("error: {}", e);
+        std::process::exit(EXIT_RUNTIME_ERROR);
+    }
+    match value {
+        Value::Int(status) if (0..=255).contains(&status) => std::process::exit(status as i32),
+        status @ (Value::Int(_) | Value::BigInt(_)) => {
+            eprintln!("error: main returned {}, an exit status is between 0 and 255", status);
+            std::process::exit(EXIT_RUNTIME_ERROR);
+        },
+        _ => ()
+    }
+}
+
+fn exit_on_runtime_error(e: &RuntimeError) -> ! {
+    eprintln!("error: {}", e);
+    std::process::exit(if e.limit.is_some() { EXIT_LIMIT_EXCEEDED } else { EXIT_RUNTIME_ERROR });
+}
+
+// Everything is checked and compiled once, running the artifact only needs the virtual machine
+fn build_artifact(source_vec: Vec<SourceFile>, output: &str, args: &Args) {
+    if args.lazy {
+        eprintln!("error: an artifact runs on the virtual machine, which doesn't evaluate lazily");
+        std::process::exit(1);
+    }
+    let sources = source_vec.iter().map(SourceDigest::of).collect();
+    let ast = build_program(source_vec, args);
+    let program = ironcamel::bytecode::compile_program(&ironcamel::lower::lower_program(&ast));
+    if program.main.is_none() {
+        eprintln!("error: function main not found");
+        std::process::exit(1);
+    }
+    if let Err(e) = fs::write(output, Artifact { sources, program }.to_bytes()) {
+        eprintln!("error: can't write {}: {}", output, e);
+        std::process::exit(1);
+    }
+}
+
+// The libraries are part of the artifact, --include isn't needed
+fn run_artifact(path: &str, args: &Args) {
+    if args.lazy {
+        eprintln!("error: {} runs on the virtual machine, which doesn't evaluate lazily", path);
+        std::process::exit(1);
+    }
+    let loaded = fs::read(path)
+        .map_err(|e| format!("can't read it: {}", e))
+        .and_then(|bytes| Artifact::from_bytes(&bytes));
+    let artifact = match loaded {
+        Ok(artifact) => artifact,
+        Err(e) => {
+            eprintln!("error: {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let stale = artifact.stale_sources();
+    if !stale.is_empty() {
+        eprintln!("error: {} is stale, {} changed since it was built, rebuild it", path, stale.join(", "));
+        std::process::exit(1);
+    }
+    run_main(args, |options| ironcamel::interpreter::eval_bytecode(&artifact.program, options));
+}
+
+fn run_tests(ast: &ProgramAST, options: &EvalOptions) {
+    println!("running {} tests", ast.tests.len());
+    let outcomes = ironcamel::interpreter::run_tests(ast, options);
+    let mut failed = Vec::new();
+    for outcome in &outcomes {
+        match &outcome.failure {
+            None => println!("test {} ... ok", outcome.name),
+            Some(_) => {
+                println!("test {} ... FAILED", outcome.name);
+                failed.push(outcome);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        println!("\nfailures:");
+        for outcome in &failed {
+            // Keep the stack trace under the test name
+            let failure = outcome.failure.as_ref().unwrap().replace('\n', "\n    ");
+            println!("    {}: {}", outcome.name, failure);
+        }
+    }
+    let result = if failed.is_empty() { "ok" } else { "FAILED" };
+    println!("\ntest result: {}. {} passed; {} failed",
+             result, outcomes.len() - failed.len(), failed.len());
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+}
+
 fn main() {
     env_logger::builder()
         .format(|buf, record| {
@@ -61,30 +290,34 @@ fn main() {
 
     info!("Args {:?}", &args);
 
-    let mut source_vec = Vec::with_capacity(args.include.len() + 1);
-    for lib_path in &args.include {
-        match fs::read_to_string(&lib_path) {
-            Ok(s) => { source_vec.push(s); }
-            Err(e) => { error!("Read lib {} failed: {}, skipping\n", lib_path, e) }
+    if let Some(path) = args.run.as_ref().filter(|p| artifact::is_artifact_path(p)) {
+        run_artifact(path, &args);
+        return;
+    }
+    let mut source_vec = read_libraries(&args.include);
+    if let Some(Command::Test { files }) = &args.command {
+        for path in files {
+            source_vec.push(read_source_file(path));
         }
+        let ast = build_program(source_vec, &args);
+        run_tests(&ast, &eval_options(&args));
+        return;
+    }
+    if let Some(Command::Build { file, output }) = &args.command {
+        source_vec.push(read_source_file(file));
+        let output = output.to_owned().unwrap_or_else(|| {
+            std::path::Path::new(file).with_extension(artifact::ARTIFACT_EXTENSION).display().to_string()
+        });
+        build_artifact(source_vec, &output, &args);
+        return;
     }
     let (run_mode, main_code) = read_source_code(&args);
     source_vec.push(main_code);
-    let source_code = source_vec.join("\n");
-
-    debug!("Source code:\n{}", &source_code);
-
-    let token_stream = ironcamel::tokenizer::convert_source_to_tokens(&source_code);
-    info!("{:?}", &token_stream);
-
-    let ast = ironcamel::parser::build_ast(&token_stream);
-    info!("{:?}", &ast);
-    let ast = pipeline::tree_transform(ast);
-    debug!("{:?}", &ast);
+    let ast = build_program(source_vec, &args);
 
     match run_mode {
         RunMode::AdHoc => {
-            ironcamel::interpreter::eval(&ast);
+            run_main(&args, |options| ironcamel::interpreter::eval_with_options(&ast, options));
         },
         RunMode::CompileToLLVMIR => {
             info!("to compile to llvm IR");
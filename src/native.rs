@@ -0,0 +1,72 @@
+// Builtins written in Rust by a host application, see embed.rs.
+// A native is called like the builtins of builtin.rs: by its name, with evaluated arguments, and an Err
+// becomes a runtime error with a stack trace. Even in the lazy mode there is no thunk left in its arguments.
+
+use std::rc::Rc;
+use crate::builtin;
+use crate::tokenizer::{convert_source_to_tokens, Token};
+use crate::value::Value;
+
+pub type NativeFunction = dyn Fn(&[Value]) -> Result<Value, String>;
+
+#[derive(Clone)]
+pub struct Native {
+    // Leaked once when it's registered, so that it's a &'static str like the name of any other builtin
+    pub name: &'static str,
+    // None if it receives a variable number of parameters
+    pub arity: Option<usize>,
+    function: Rc<NativeFunction>,
+}
+
+#[derive(Clone, Default)]
+pub struct Natives {
+    natives: Vec<Native>,
+}
+
+impl Natives {
+    // Registering a name again replaces the function
+    pub fn register(&mut self, name: &str, arity: Option<usize>,
+                    function: impl Fn(&[Value]) -> Result<Value, String> + 'static) -> Result<(), String> {
+        if !is_identifier(name) {
+            return Err(format!("`{}` is not a valid name for a builtin", name));
+        }
+        if builtin::find_builtin(name).is_some() || builtin::IO_OPERATIONS.contains(&name) {
+            return Err(format!("`{}` is already a builtin", name));
+        }
+        let function: Rc<NativeFunction> = Rc::new(function);
+        match self.natives.iter_mut().find(|n| n.name == name) {
+            Some(native) => {
+                native.arity = arity;
+                native.function = function;
+            },
+            None => {
+                let name = Box::leak(name.to_owned().into_boxed_str());
+                self.natives.push(Native { name, arity, function });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Native> {
+        self.natives.iter().find(|n| n.name == name)
+    }
+
+    // None if there is no such native
+    pub(crate) fn call(&self, name: &str, params: &[Value]) -> Option<Result<Value, String>> {
+        let native = self.find(name)?;
+        match native.arity {
+            Some(arity) if arity != params.len() => {
+                Some(Err(format!("{} expects {} arguments, got {}", name, arity, params.len())))
+            },
+            _ => Some((native.function)(params))
+        }
+    }
+}
+
+// The tokenizer reads keywords even at the start of a longer name, so the name has to come back in one piece
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && matches!(&convert_source_to_tokens(name)[..], [Token::IdentifierToken(s)] if s == name)
+}
@@ -0,0 +1,283 @@
+// Constant folding and algebraic simplification on the AST.
+// As all expressions are pure, an expression can be replaced by anything which evaluates to the same value.
+//
+//   *(2, 3)                 -> 6
+//   ==(1, 1)                -> true
+//   if true then {a} else {b} -> a
+//   hd(list(x, ...))        -> x
+//   tl(list(x, y, ...))     -> list(y, ...)
+//   is_empty(list(...))     -> true / false
+//   cons(x, list(...))      -> list(x, ...)
+// The list items hd, tl and is_empty drop must not fail, `hd(list(1, hd(list())))` is still an error.
+//   &&(true, x)             -> x, and so on for the special forms ||, !
+//
+// A call is only folded when its callee is really the builtin, not a local variable with the same name.
+// Arithmetic that would overflow is left to the runtime, which makes a big integer of it.
+
+use std::collections::HashSet;
+use std::rc::Rc;
+use crate::builtin::ARITHMETIC_OPERATORS;
+use crate::expr::{cannot_fail, ClosureAST, ExprAST, IfElseExpr};
+use crate::parser::{BlockAST, FunctionAST, ProgramAST, StatementAST, TestAST};
+
+pub fn fold_constants(ast: ProgramAST) -> ProgramAST {
+    let functions = ast.functions.into_iter().map(fold_function).collect();
+    let tests = ast.tests.into_iter().map(|t| TestAST {
+        block: fold_block(t.block, &HashSet::new()),
+        ..t
+    }).collect();
+    ProgramAST { functions, tests }
+}
+
+fn fold_function(fun: FunctionAST) -> FunctionAST {
+    let local: HashSet<String> = fun.arguments.iter().cloned().collect();
+    let body = fold_block(fun.body, &local);
+    FunctionAST { body, ..fun }
+}
+
+// The block is only copied if it's shared
+fn fold_block(block: Rc<BlockAST>, local: &HashSet<String>) -> Rc<BlockAST> {
+    let block = Rc::unwrap_or_clone(block);
+    let mut local = local.clone();
+    let statements = fold_statements(block.statements, &mut local);
+    let return_expr = Box::new(fold_expr(*block.return_expr, &local));
+    Rc::new(BlockAST { statements, return_expr })
+}
+
+fn fold_statements(statements: Vec<StatementAST>, local: &mut HashSet<String>) -> Vec<StatementAST> {
+    let mut result = Vec::with_capacity(statements.len());
+    for s in statements {
+        let folded = match s {
+            StatementAST::Bind(mut lb) => {
+                lb.expr = Box::new(fold_expr(*lb.expr, local));
+                local.insert(lb.variable.to_owned());
+                StatementAST::Bind(lb)
+            },
+            StatementAST::Read(r) => {
+                local.insert(r.write_to_variable.to_owned());
+                StatementAST::Read(r)
+            },
+            StatementAST::Write(mut w) => {
+                w.expr = Box::new(fold_expr(*w.expr, local));
+                StatementAST::Write(w)
+            },
+            StatementAST::FileOpen(_) | StatementAST::Error => s
+        };
+        result.push(folded);
+    }
+    result
+}
+
+// A block without statements is just its return expression
+fn block_to_expr(block: Rc<BlockAST>) -> ExprAST {
+    if block.statements.is_empty() {
+        *Rc::unwrap_or_clone(block).return_expr
+    } else {
+        ExprAST::Block(block)
+    }
+}
+
+fn fold_expr(expr: ExprAST, local: &HashSet<String>) -> ExprAST {
+    match expr {
+        ExprAST::CallCallableObjectByname(func_name, params, line) => {
+            let params: Vec<Box<ExprAST>> = params.into_iter()
+                .map(|p| Box::new(fold_expr(*p, local)))
+                .collect();
+            match fold_builtin_call(&func_name, &params, line, local) {
+                Some(folded) => folded,
+                None => ExprAST::CallCallableObjectByname(func_name, params, line)
+            }
+        },
+        ExprAST::If(if_expr) => {
+            let condition = fold_expr(*if_expr.condition, local);
+            match condition {
+                ExprAST::Bool(true) => block_to_expr(fold_block(if_expr.then_case, local)),
+                ExprAST::Bool(false) => block_to_expr(fold_block(if_expr.else_case, local)),
+                _ => ExprAST::If(IfElseExpr {
+                    condition: Box::new(condition),
+                    then_case: fold_block(if_expr.then_case, local),
+                    else_case: fold_block(if_expr.else_case, local)
+                })
+            }
+        },
+        ExprAST::Block(block) => block_to_expr(fold_block(block, local)),
+        ExprAST::Closure(clos) => {
+            let clos = Rc::unwrap_or_clone(clos);
+            let mut captured = local.clone();
+            captured.extend(clos.params.iter().cloned());
+            let block = fold_block(clos.block, &captured);
+            ExprAST::Closure(Rc::new(ClosureAST { params: clos.params, block }))
+        },
+        _ => expr
+    }
+}
+
+fn is_builtin_call<'a>(expr: &'a ExprAST, builtin: &str, local: &HashSet<String>) -> Option<&'a [Box<ExprAST>]> {
+    match expr {
+        ExprAST::CallCallableObjectByname(func_name, params, _)
+            if func_name == builtin && !local.contains(func_name) => Some(params),
+        _ => None
+    }
+}
+
+fn all_cannot_fail(items: &[Box<ExprAST>]) -> bool {
+    items.iter().all(|x| cannot_fail(x))
+}
+
+// Boxed like the params of ExprAST::CallCallableObjectByname
+#[allow(clippy::vec_box)]
+fn build_list_call(items: Vec<Box<ExprAST>>, line: usize) -> ExprAST {
+    ExprAST::CallCallableObjectByname(String::from("list"), items, line)
+}
+
+// None if the call can't be simplified. A list built instead is at the `line` of the call
+fn fold_builtin_call(func_name: &str, params: &[Box<ExprAST>], line: usize, local: &HashSet<String>) -> Option<ExprAST> {
+    if local.contains(func_name) {
+        return None;
+    }
+    if ARITHMETIC_OPERATORS.contains(&func_name) {
+        if params.len() != 2 {
+            return None;
+        }
+        return match (&*params[0], &*params[1]) {
+            (ExprAST::Int(a), ExprAST::Int(b)) => fold_arithmetic(func_name, *a, *b),
+            _ => None
+        };
+    }
+    match (func_name, params) {
+        ("hd", [l]) => {
+            let items = is_builtin_call(l, "list", local)?;
+            let (first, rest) = items.split_first()?;
+            all_cannot_fail(rest).then(|| (**first).clone())
+        },
+        ("tl", [l]) => {
+            let items = is_builtin_call(l, "list", local)?;
+            let (first, rest) = items.split_first()?;
+            cannot_fail(first).then(|| build_list_call(rest.to_vec(), line))
+        },
+        ("is_empty", [l]) => {
+            let items = is_builtin_call(l, "list", local)?;
+            all_cannot_fail(items).then_some(ExprAST::Bool(items.is_empty()))
+        },
+        ("&&", [a, b]) => match &**a {
+            ExprAST::Bool(true) => Some((**b).clone()),
+            ExprAST::Bool(false) => Some(ExprAST::Bool(false)),
+            _ => None
+        },
+        ("||", [a, b]) => match &**a {
+            ExprAST::Bool(true) => Some(ExprAST::Bool(true)),
+            ExprAST::Bool(false) => Some((**b).clone()),
+            _ => None
+        },
+        ("!", [a]) => match &**a {
+            ExprAST::Bool(x) => Some(ExprAST::Bool(!x)),
+            _ => None
+        },
+        ("cons", [x, l]) => {
+            let items = is_builtin_call(l, "list", local)?;
+            let mut result = Vec::with_capacity(items.len() + 1);
+            result.push(x.clone());
+            result.extend(items.iter().cloned());
+            Some(build_list_call(result, line))
+        },
+        _ => None
+    }
+}
+
+fn fold_arithmetic(op: &str, a: i64, b: i64) -> Option<ExprAST> {
+    match op {
+        "+" => a.checked_add(b).map(ExprAST::Int),
+        "-" => a.checked_sub(b).map(ExprAST::Int),
+        "*" => a.checked_mul(b).map(ExprAST::Int),
+        // So is division by zero, for its runtime error
+        "/" => a.checked_div(b).map(ExprAST::Int),
+        "%" => a.checked_rem(b).map(ExprAST::Int),
+        "==" => Some(ExprAST::Bool(a == b)),
+        "!=" => Some(ExprAST::Bool(a != b)),
+        ">" => Some(ExprAST::Bool(a > b)),
+        "<" => Some(ExprAST::Bool(a < b)),
+        ">=" => Some(ExprAST::Bool(a >= b)),
+        "<=" => Some(ExprAST::Bool(a <= b)),
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use crate::debug_output::build_expr_debug_strings;
+    use crate::optimize::fold_constants;
+    use crate::parser::build_ast;
+    use crate::tokenizer::convert_source_to_tokens;
+
+    fn folded_return_expr(code: &str) -> String {
+        let ast = fold_constants(build_ast(&convert_source_to_tokens(code)));
+        build_expr_debug_strings(&ast.functions[0].body.return_expr).join(" ")
+    }
+
+    #[test]
+    fn fold_arithmetic() {
+        assert_eq!(folded_return_expr("fn f() { +(*(2, 3), 4) }"), "Integer: 10");
+        assert_eq!(folded_return_expr("fn f() { ==(1, 1) }"), "Bool: true");
+        assert_eq!(folded_return_expr("fn f(x) { +(x, *(2, 3)) }"),
+                   "Call: + |-- Variable: x |-- Integer: 6");
+    }
+
+    #[test]
+    fn keep_overflow_to_runtime() {
+        assert_eq!(folded_return_expr("fn f() { *(9223372036854775807, 2) }"),
+                   "Call: * |-- Integer: 9223372036854775807 |-- Integer: 2");
+        assert_eq!(folded_return_expr("fn f() { /(7, 0) }"), "Call: / |-- Integer: 7 |-- Integer: 0");
+        assert_eq!(folded_return_expr("fn f() { %(-(0, 7), 2) }"), "Integer: -1");
+    }
+
+    #[test]
+    fn fold_shared_bodies() {
+        let ast = build_ast(&convert_source_to_tokens("fn f() { +(1, 2) }"));
+        let copy = ast.functions[0].clone();
+        assert!(Rc::ptr_eq(&copy.body, &ast.functions[0].body));
+        let folded = fold_constants(ast);
+        assert_eq!(build_expr_debug_strings(&folded.functions[0].body.return_expr).join(" "), "Integer: 3");
+        assert_eq!(build_expr_debug_strings(&copy.body.return_expr).join(" "),
+                   "Call: + |-- Integer: 1 |-- Integer: 2");
+    }
+
+    #[test]
+    fn prune_branches() {
+        assert_eq!(folded_return_expr("fn f(a, b) { if >(2, 1) then { a } else { b } }"), "Variable: a");
+    }
+
+    #[test]
+    fn simplify_lists() {
+        assert_eq!(folded_return_expr("fn f(a, b) { hd(list(a, b)) }"), "Variable: a");
+        assert_eq!(folded_return_expr("fn f(a, b) { is_empty(tl(list(a))) }"), "Bool: true");
+        assert_eq!(folded_return_expr("fn f(a, b) { cons(a, list(b)) }"),
+                   "Call: list |-- Variable: a |-- Variable: b");
+    }
+
+    #[test]
+    fn keep_list_items_which_fail() {
+        assert_eq!(folded_return_expr("fn f() { hd(list(1, hd(list()))) }"),
+                   "Call: hd |-- Call: list |-- Integer: 1 |-- Call: hd |-- Call: list");
+        assert_eq!(folded_return_expr("fn f(a) { tl(list(hd(a), 2)) }"),
+                   "Call: tl |-- Call: list |-- Call: hd |-- Variable: a |-- Integer: 2");
+        assert_eq!(folded_return_expr("fn f(a) { is_empty(list(hd(a))) }"),
+                   "Call: is_empty |-- Call: list |-- Call: hd |-- Variable: a");
+        assert_eq!(folded_return_expr("fn f(a) { hd(list(hd(a), 2)) }"), "Call: hd |-- Variable: a");
+    }
+
+    #[test]
+    fn fold_logical_operators() {
+        assert_eq!(folded_return_expr("fn f(a) { &&(>(2, 1), a) }"), "Variable: a");
+        assert_eq!(folded_return_expr("fn f(a) { ||(!(true), hd(a)) }"), "Call: hd |-- Variable: a");
+        assert_eq!(folded_return_expr("fn f(a) { ||(a, true) }"),
+                   "Call: || |-- Variable: a |-- Bool: true");
+    }
+
+    #[test]
+    fn respect_local_names() {
+        assert_eq!(folded_return_expr("fn f(hd, b) { hd(list(b)) }"),
+                   "Call: hd |-- Call: list |-- Variable: b");
+    }
+}
@@ -1,3 +1,4 @@
+use std::rc::Rc;
 use log::{debug, info, warn};
 use crate::debug_output::build_statement_debug_strings;
 use crate::expr::{ExprAST, try_read_expr};
@@ -11,14 +12,29 @@ pub trait AST {
 }
 
 pub struct ProgramAST {
-    pub functions : Vec<FunctionAST>
+    pub functions : Vec<FunctionAST>,
+    pub tests: Vec<TestAST>
 }
 #[derive(Clone)]
 pub struct FunctionAST {
     pub function_name : String,
     pub arguments: Vec<String>,
-    pub statements : Vec<StatementAST>,
-    pub return_expr: Box<ExprAST>
+    // Bodies are shared, never copied: by the passes over the AST and whatever keeps the program around
+    pub body: Rc<BlockAST>,
+    // The source file defining this function, filled by the pipeline
+    pub origin: String,
+    // The line of `fn`, 0 if unknown
+    pub line: usize
+}
+// test "name" { ... }
+// Only collected by `ironcamel test`, never executed by --run
+#[derive(Clone)]
+pub struct TestAST {
+    pub name: String,
+    pub block: Rc<BlockAST>,
+    // Same as in FunctionAST
+    pub origin: String,
+    pub line: usize
 }
 #[derive(Clone)]
 pub struct BlockAST {
@@ -26,12 +42,6 @@ pub struct BlockAST {
     pub return_expr: Box<ExprAST>
 }
 
-pub fn function2block(ast: FunctionAST) -> BlockAST {
-    BlockAST {
-        statements: ast.statements,
-        return_expr: ast.return_expr
-    }
-}
 #[derive(Clone)]
 pub enum StatementAST {
     Bind(LetBindingAST),
@@ -44,21 +54,102 @@ pub enum StatementAST {
 
 
 pub fn build_ast(tokens: &Vec<Token>) -> ProgramAST {
+    build_ast_with_lines(tokens, &[])
+}
+
+// `lines` holds the line of every token, see convert_source_to_tokens_with_lines
+pub fn build_ast_with_lines(tokens: &Vec<Token>, lines: &[usize]) -> ProgramAST {
     warn!("Building ast");
     debug!("{:?}", tokens);
     let mut functions = Vec::new();
+    let mut tests = Vec::new();
     let mut pos = 0;
     while pos < tokens.len() {
         if tokens[pos] == SpaceToken {
             pos +=1 ;
             continue;
         }
-        let (fun_ast, len) = read_function(tokens, pos);
+        if is_test_head(tokens, pos) {
+            let (mut test_ast, len) = read_test(tokens, pos);
+            test_ast.line = lines.get(pos).copied().unwrap_or(0);
+            locate_calls_in_block(&mut test_ast.block, lines);
+            debug!("Got test {}", &test_ast.name);
+            tests.push(test_ast);
+            pos += len;
+            continue;
+        }
+        let (mut fun_ast, len) = read_function(tokens, pos);
+        fun_ast.line = lines.get(pos).copied().unwrap_or(0);
+        locate_calls_in_block(&mut fun_ast.body, lines);
         debug!("Got fun");
         functions.push(fun_ast);
         pos += len;
     }
-    ProgramAST{functions}
+    ProgramAST{functions, tests}
+}
+
+// Replace the token position the parser left in every call by its line
+fn locate_calls_in_block(block: &mut Rc<BlockAST>, lines: &[usize]) {
+    let block = Rc::get_mut(block).expect("A block just parsed is not shared");
+    for s in &mut block.statements {
+        match s {
+            StatementAST::Bind(lb) => locate_calls(&mut lb.expr, lines),
+            StatementAST::Write(w) => locate_calls(&mut w.expr, lines),
+            _ => ()
+        }
+    }
+    locate_calls(&mut block.return_expr, lines);
+}
+
+fn locate_calls(expr: &mut ExprAST, lines: &[usize]) {
+    match expr {
+        ExprAST::CallCallableObjectByname(_, params, line) => {
+            *line = lines.get(*line).copied().unwrap_or(0);
+            for p in params {
+                locate_calls(p, lines);
+            }
+        },
+        ExprAST::If(if_expr) => {
+            locate_calls(&mut if_expr.condition, lines);
+            locate_calls_in_block(&mut if_expr.then_case, lines);
+            locate_calls_in_block(&mut if_expr.else_case, lines);
+        },
+        ExprAST::Block(block) => locate_calls_in_block(block, lines),
+        ExprAST::Closure(clos) => {
+            let clos = Rc::get_mut(clos).expect("A closure just parsed is not shared");
+            locate_calls_in_block(&mut clos.block, lines);
+        },
+        _ => ()
+    }
+}
+
+// `test` is not a keyword, so that it can still be used as an identifier.
+// A test is recognized by the identifier test followed by a string literal
+fn is_test_head(tokens: &[Token], pos: usize) -> bool {
+    if pos + 1 >= tokens.len() {
+        return false;
+    }
+    match (&tokens[pos], &tokens[pos+1]) {
+        (IdentifierToken(s), Token::LiteralString(_)) => s == "test",
+        _ => false
+    }
+}
+
+fn read_test(tokens: &Vec<Token>, pos: usize) -> (TestAST, usize) {
+    let mut len = 0;
+    assert!(is_test_head(tokens, pos));
+    len += 1;
+
+    let name = match &tokens[pos+len] {
+        Token::LiteralString(s) => s.to_owned(),
+        _ => panic!("Expect the name of the test, got {:?}", tokens[pos+len])
+    };
+    len += 1;
+
+    let (block, block_len) = read_block(tokens, pos+len);
+    len += block_len;
+
+    (TestAST{ name, block, origin: String::new(), line: 0 }, len)
 }
 
 fn read_function(tokens: &Vec<Token>, pos: usize) -> (FunctionAST, usize) {
@@ -94,8 +185,9 @@ fn read_function(tokens: &Vec<Token>, pos: usize) -> (FunctionAST, usize) {
     let fun = FunctionAST{
         function_name: function_name.clone(),
         arguments,
-        statements : block.statements,
-        return_expr: block.return_expr
+        body: block,
+        origin: String::new(),
+        line: 0
     };
     info!("Read a function \n{:?}", fun.debug_strings());
 
@@ -126,7 +218,7 @@ pub fn read_argument_list(tokens: &Vec<Token>, pos: usize) -> (Vec<String>, usiz
     (result, len)
 }
 
-pub(crate) fn read_block(tokens: &Vec<Token>, pos: usize) -> (BlockAST, usize) {
+pub(crate) fn read_block(tokens: &Vec<Token>, pos: usize) -> (Rc<BlockAST>, usize) {
     let mut len = 0;
 
     assert_eq!(tokens[pos + len], LeftCurlyBracket);
@@ -158,7 +250,7 @@ pub(crate) fn read_block(tokens: &Vec<Token>, pos: usize) -> (BlockAST, usize) {
     assert_eq!(tokens[pos + len], RightCurlyBracket);
     len += 1;
 
-    let block = BlockAST{ statements, return_expr: Box::new(return_expr) };
+    let block = Rc::new(BlockAST{ statements, return_expr: Box::new(return_expr) });
     (block, len)
 }
 
@@ -1,7 +1,99 @@
+
 // The term pipeline is inspired by cython.
 
+use std::collections::HashSet;
+use log::{debug, info};
+use crate::lint::{eliminate_dead_code, lint_program, LintWarning};
+use crate::native::Natives;
+use crate::optimize::fold_constants;
 use crate::parser::ProgramAST;
+use crate::resolve::{check_program_with_natives, ResolveError};
 
-pub fn tree_transform(ast: ProgramAST) -> ProgramAST{
+pub struct SourceFile {
+    pub path: String,
+    pub code: String,
+    // Included by --include, part of the prelude
+    pub is_library: bool
+}
+
+#[derive(Default)]
+pub struct PipelineOptions {
+    // Remove unused let bindings and unreachable functions before evaluation
+    pub eliminate_dead_code: bool,
+    // Builtins registered by the host, see embed.rs
+    pub natives: Natives
+}
+
+pub struct CompiledProgram {
+    pub ast: ProgramAST,
+    pub warnings: Vec<LintWarning>
+}
+
+// Source code -> tokens -> AST -> checked AST -> transformed AST
+// Every file is parsed on its own, so that we know where a function comes from
+pub fn build_program(sources: &[SourceFile], options: &PipelineOptions) -> Result<CompiledProgram, Vec<ResolveError>> {
+    let mut program = ProgramAST { functions: Vec::new(), tests: Vec::new() };
+    for source in sources {
+        let ast = parse_source(source);
+        program.functions.extend(ast.functions);
+        program.tests.extend(ast.tests);
+    }
+    check_program_with_natives(&program, &options.natives)?;
+
+    let libraries: HashSet<String> = sources.iter()
+        .filter(|s| s.is_library)
+        .map(|s| s.path.to_owned())
+        .collect();
+    let warnings = lint_program(&program, &libraries);
+    if options.eliminate_dead_code {
+        program = eliminate_dead_code(program);
+    }
+
+    let ast = tree_transform(program);
+    debug!("{:?}", &ast);
+    Ok(CompiledProgram { ast, warnings })
+}
+
+fn parse_source(source: &SourceFile) -> ProgramAST {
+    debug!("Source code of {}:\n{}", &source.path, &source.code);
+
+    let (token_stream, lines) = crate::tokenizer::convert_source_to_tokens_with_lines(&source.code);
+    info!("{:?}", &token_stream);
+
+    let mut ast = crate::parser::build_ast_with_lines(&token_stream, &lines);
+    for fun in &mut ast.functions {
+        fun.origin = source.path.to_owned();
+    }
+    for test in &mut ast.tests {
+        test.origin = source.path.to_owned();
+    }
+    info!("{:?}", &ast);
     ast
-}
\ No newline at end of file
+}
+
+// The interpreter and gen_ir both work on the transformed AST
+pub fn tree_transform(ast: ProgramAST) -> ProgramAST{
+    fold_constants(ast)
+}
+
+// Builds programs for the unit tests of the other modules, like main.rs does
+#[cfg(test)]
+pub(crate) mod test_support {
+    use crate::parser::ProgramAST;
+    use crate::pipeline::{build_program, PipelineOptions, SourceFile};
+
+    // The libraries are read from their paths, `main` is the code of the program itself
+    pub(crate) fn build_with_libraries(libraries: &[&str], main: &str) -> ProgramAST {
+        let mut sources: Vec<SourceFile> = libraries.iter().map(|path| SourceFile {
+            path: path.to_string(),
+            code: std::fs::read_to_string(path).unwrap(),
+            is_library: true
+        }).collect();
+        sources.push(SourceFile { path: String::from("main"), code: main.to_owned(), is_library: false });
+        build_program(&sources, &PipelineOptions::default()).unwrap_or_else(|errors| panic!("{:?}", errors)).ast
+    }
+
+    pub(crate) fn build(main: &str) -> ProgramAST {
+        build_with_libraries(&[], main)
+    }
+}
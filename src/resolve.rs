@@ -0,0 +1,372 @@
+// Static checks before execution. The interpreter would panic on all of these,
+// but only when it happens to reach them.
+//
+// The scoping rules follow the interpreter:
+//   - Only functions live in the global scope, main is not callable
+//   - A block in an if branch sees everything bound before the if, its own bindings are dropped afterwards
+//   - A closure captures every local binding at the place it's created
+//   - let can't shadow a global function or a local binding
+//   - A parameter may shadow a local binding, but not a global function
+//   - Both may shadow a builtin: in their scope the name is the local one, as a value and as a callee
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::builtin;
+use crate::expr::ExprAST;
+use crate::native::Natives;
+use crate::parser::{BlockAST, FunctionAST, ProgramAST, StatementAST};
+
+const READ_PROCEDURES: &[&str; 2] = &["readstr", "env"];
+const WRITE_PROCEDURES: &[&str; 2] = &["writeline", "writelist"];
+
+pub struct ResolveError {
+    // Such as `fn fold` or `test "reverse"`
+    pub location: String,
+    pub message: String
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+impl fmt::Debug for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(PartialEq)]
+enum FileMode {
+    Read,
+    Write
+}
+
+struct Resolver<'a> {
+    global: HashMap<&'a str, &'a FunctionAST>,
+    natives: &'a Natives,
+    location: String,
+    errors: Vec<ResolveError>
+}
+
+pub fn check_program(ast: &ProgramAST) -> Result<(), Vec<ResolveError>> {
+    check_program_with_natives(ast, &Natives::default())
+}
+
+// The natives are builtins too
+pub fn check_program_with_natives(ast: &ProgramAST, natives: &Natives) -> Result<(), Vec<ResolveError>> {
+    let mut resolver = Resolver {
+        global: HashMap::new(),
+        natives,
+        location: String::new(),
+        errors: Vec::new()
+    };
+    resolver.collect_global_functions(ast);
+
+    for fun in &ast.functions {
+        resolver.location = format!("fn {}", fun.function_name);
+        let mut local: HashSet<String> = HashSet::new();
+        for arg in &fun.arguments {
+            resolver.check_parameter(arg);
+            if !local.insert(arg.to_owned()) {
+                resolver.error(format!("parameter `{}` is declared more than once", arg));
+            }
+        }
+        if fun.function_name == "main" {
+            if fun.arguments.len() > 1 {
+                resolver.error(String::from("main takes no parameters, or one for the command line arguments"));
+            }
+            resolver.check_impure_block(local, &fun.body.statements, &fun.body.return_expr);
+        } else {
+            resolver.check_block(local, &fun.body.statements, &fun.body.return_expr);
+        }
+    }
+    for test in &ast.tests {
+        resolver.location = format!("test {:?}", test.name);
+        resolver.check_impure_block(HashSet::new(), &test.block.statements, &test.block.return_expr);
+    }
+
+    if resolver.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(resolver.errors)
+    }
+}
+
+impl<'a> Resolver<'a> {
+    fn error(&mut self, message: String) {
+        self.errors.push(ResolveError { location: self.location.to_owned(), message });
+    }
+
+    fn collect_global_functions(&mut self, ast: &'a ProgramAST) {
+        let mut defined_in: HashMap<&str, &str> = HashMap::new();
+        for fun in &ast.functions {
+            let name = fun.function_name.as_str();
+            match defined_in.get(name) {
+                Some(first) => {
+                    self.location = format!("fn {}", name);
+                    let message = if first.is_empty() && fun.origin.is_empty() {
+                        String::from("function is defined more than once")
+                    } else {
+                        format!("function is defined in both {} and {}", first, fun.origin)
+                    };
+                    self.error(message);
+                },
+                None => { defined_in.insert(name, &fun.origin); }
+            }
+            if name == "main" {
+                continue;
+            }
+            self.global.entry(name).or_insert(fun);
+        }
+    }
+
+    fn is_builtin(&self, name: &str) -> bool {
+        builtin::ARITHMETIC_OPERATORS.contains(&name) ||
+            builtin::IRONCAMEL_BUILTIN_FUNCTIONS.contains(&name) ||
+            self.natives.find(name).is_some()
+    }
+
+    fn builtin_arity(&self, name: &str) -> Option<usize> {
+        match self.natives.find(name) {
+            Some(native) => native.arity,
+            None => builtin::builtin_arity(name)
+        }
+    }
+
+    // A builtin may be shadowed, lower.rs looks up locals first. A global function may not,
+    // lint.rs counts every mention of its name as a use
+    fn check_parameter(&mut self, param: &str) {
+        if self.global.contains_key(param) {
+            self.error(format!("parameter `{}` shadows a global function", param));
+        }
+    }
+
+    fn bind(&mut self, local: &mut HashSet<String>, var: &str) {
+        if self.global.contains_key(var) || local.contains(var) {
+            self.error(format!("`{}` is already in env! No shadowing allowed!", var));
+        }
+        local.insert(var.to_owned());
+    }
+
+    // The top level of main and of tests, the only places where IO is allowed
+    fn check_impure_block(&mut self, mut local: HashSet<String>,
+                          statements: &Vec<StatementAST>, return_expr: &ExprAST) {
+        let mut files: HashMap<String, FileMode> = HashMap::new();
+        files.insert(String::from("stdin"), FileMode::Read);
+        files.insert(String::from("stdout"), FileMode::Write);
+        files.insert(String::from("process"), FileMode::Read);
+        for s in statements {
+            match s {
+                StatementAST::Bind(lb) => {
+                    self.check_expr(&local, &lb.expr);
+                    self.bind(&mut local, &lb.variable);
+                },
+                StatementAST::Read(r) => {
+                    if !READ_PROCEDURES.contains(&r.impure_procedure_name.as_str()) {
+                        self.error(format!("unknown read procedure `{}`", r.impure_procedure_name));
+                    }
+                    // env@process >> HOME binds HOME to the environment variable of the same name
+                    match (r.impure_procedure_name.as_str(), r.file_handler.as_str()) {
+                        ("env", "process") => (),
+                        ("env", handler) => self.error(format!("`env` reads from `process`, not `{}`", handler)),
+                        (_, "process") => self.error(String::from("`process` can only be read by `env`")),
+                        _ => self.check_file_handler(&files, &r.file_handler, FileMode::Read)
+                    }
+                    self.bind(&mut local, &r.write_to_variable);
+                },
+                StatementAST::Write(w) => {
+                    if !WRITE_PROCEDURES.contains(&w.impure_procedure_name.as_str()) {
+                        self.error(format!("unknown write procedure `{}`", w.impure_procedure_name));
+                    }
+                    self.check_file_handler(&files, &w.file_handler, FileMode::Write);
+                    self.check_expr(&local, &w.expr);
+                },
+                StatementAST::FileOpen(fo) => {
+                    let mode = match fo.impure_procedure_name.as_str() {
+                        "fopen_read" => FileMode::Read,
+                        "fopen_write" => FileMode::Write,
+                        _ => {
+                            self.error(format!("unknown FileOpen procedure `{}`", fo.impure_procedure_name));
+                            continue;
+                        }
+                    };
+                    files.insert(fo.file_handler.to_owned(), mode);
+                },
+                StatementAST::Error => self.error(String::from("invalid statement")),
+            }
+        }
+        self.check_expr(&local, return_expr);
+    }
+
+    fn check_file_handler(&mut self, files: &HashMap<String, FileMode>, handler: &str, mode: FileMode) {
+        match files.get(handler) {
+            None => self.error(format!("file handler `{}` is not opened", handler)),
+            Some(m) if *m != mode => {
+                let expected = if mode == FileMode::Read { "reading" } else { "writing" };
+                self.error(format!("file handler `{}` is not opened for {}", handler, expected));
+            },
+            Some(_) => ()
+        }
+    }
+
+    fn check_block(&mut self, mut local: HashSet<String>,
+                   statements: &Vec<StatementAST>, return_expr: &ExprAST) {
+        for s in statements {
+            match s {
+                StatementAST::Bind(lb) => {
+                    self.check_expr(&local, &lb.expr);
+                    self.bind(&mut local, &lb.variable);
+                },
+                StatementAST::Read(_) | StatementAST::Write(_) | StatementAST::FileOpen(_) => {
+                    self.error(String::from("IO is only allowed at the top level of main"));
+                },
+                StatementAST::Error => self.error(String::from("invalid statement")),
+            }
+        }
+        self.check_expr(&local, return_expr);
+    }
+
+    fn check_nested_block(&mut self, local: &HashSet<String>, block: &BlockAST) {
+        self.check_block(local.clone(), &block.statements, &block.return_expr);
+    }
+
+    fn check_expr(&mut self, local: &HashSet<String>, expr: &ExprAST) {
+        match expr {
+            ExprAST::Int(_) | ExprAST::BigInt(_) | ExprAST::Bool(_) | ExprAST::StringLiteral(_) => (),
+            ExprAST::Variable(v) if builtin::LOGICAL_OPERATORS.contains(&v.as_str()) => {
+                self.error(format!("`{}` can only be called, it is not a value", v));
+            },
+            ExprAST::Variable(v) => {
+                if !local.contains(v) && !self.global.contains_key(v.as_str()) && !self.is_builtin(v) {
+                    self.error(format!("undefined variable `{}`", v));
+                }
+            },
+            ExprAST::CallCallableObjectByname(func_name, params, _) => {
+                for p in params {
+                    self.check_expr(local, p);
+                }
+                self.check_call(local, func_name, params.len());
+            },
+            ExprAST::If(if_expr) => {
+                self.check_expr(local, &if_expr.condition);
+                self.check_nested_block(local, &if_expr.then_case);
+                self.check_nested_block(local, &if_expr.else_case);
+            },
+            ExprAST::Block(block) => self.check_nested_block(local, block),
+            ExprAST::Closure(clos) => {
+                let mut captured = local.clone();
+                for p in &clos.params {
+                    self.check_parameter(p);
+                    captured.insert(p.to_owned());
+                }
+                self.check_nested_block(&captured, &clos.block);
+            },
+            ExprAST::Error => self.error(String::from("invalid expression")),
+        }
+    }
+
+    // Same lookup order as lower.rs: local, builtin, global
+    fn check_call(&mut self, local: &HashSet<String>, func_name: &str, argc: usize) {
+        if local.contains(func_name) {
+            // A local callable object, its arity is only known at runtime
+            return;
+        }
+        if self.is_builtin(func_name) || builtin::LOGICAL_OPERATORS.contains(&func_name) {
+            if let Some(arity) = self.builtin_arity(func_name) && arity != argc {
+                self.error(format!("builtin `{}` takes {} arguments, but {} were given",
+                                   func_name, arity, argc));
+            }
+            return;
+        }
+        match self.global.get(func_name) {
+            Some(fun) => {
+                if fun.arguments.len() != argc {
+                    self.error(format!("function `{}` takes {} arguments, but {} were given",
+                                       func_name, fun.arguments.len(), argc));
+                }
+            },
+            None => self.error(format!("can't find a callable object called `{}`", func_name))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::resolve::check_program;
+    use crate::tokenizer::convert_source_to_tokens;
+    use crate::parser::build_ast;
+
+    fn errors_of(code: &str) -> Vec<String> {
+        let ast = build_ast(&convert_source_to_tokens(code));
+        match check_program(&ast) {
+            Ok(()) => vec![],
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect()
+        }
+    }
+
+    #[test]
+    fn valid_program() {
+        let code = "fn add(a, b) { +(a, b) }
+            fn main() { let f = |x| { add(x, 1) }; let y = f(2); writeline@stdout << y; 0 }";
+        assert!(errors_of(code).is_empty());
+    }
+
+    #[test]
+    fn undefined_variable_and_arity() {
+        let code = "fn add(a, b) { +(a, c) }
+            fn main() { let y = add(1); writeline@stdout << hd(y, y); let z = &&; writeline@stdout << !(y, z); 0 }";
+        assert_eq!(errors_of(code), vec![
+            "fn add: undefined variable `c`",
+            "fn main: function `add` takes 2 arguments, but 1 were given",
+            "fn main: builtin `hd` takes 1 arguments, but 2 were given",
+            "fn main: `&&` can only be called, it is not a value",
+            "fn main: builtin `!` takes 1 arguments, but 2 were given",
+        ]);
+    }
+
+    #[test]
+    fn shadowing_and_duplicates() {
+        let code = "fn f(a) { let a = 1; if true then { let b = 2; b } else { let b = 3; b } }
+            fn f(x) { let f = 1; f }
+            fn g(f) { f }";
+        assert_eq!(errors_of(code), vec![
+            "fn f: function is defined more than once",
+            "fn f: `a` is already in env! No shadowing allowed!",
+            "fn f: `f` is already in env! No shadowing allowed!",
+            "fn g: parameter `f` shadows a global function",
+        ]);
+    }
+
+    #[test]
+    fn shadow_builtins() {
+        let code = "fn f(max, hd) { let lower = hd(max, 1, 2); let g = |abs| { +(abs, lower) }; g(max) }
+            fn main() { let trim = 1; writeline@stdout << f(trim, |a, b, c| { c }); 0 }";
+        assert!(errors_of(code).is_empty());
+    }
+
+    #[test]
+    fn io_outside_main() {
+        let code = "fn f(a) { writeline@stdout << a; a }
+            fn main() { readstr@fin >> s; writeline@stdin << s; 0 }";
+        assert_eq!(errors_of(code), vec![
+            "fn f: IO is only allowed at the top level of main",
+            "fn main: file handler `fin` is not opened",
+            "fn main: file handler `stdin` is not opened for writing",
+        ]);
+    }
+
+    #[test]
+    fn main_and_process() {
+        let code = "fn main(args, more) { env@process >> HOME; env@stdin >> PATH; readstr@process >> s; writeline@process << s; 0 }";
+        assert_eq!(errors_of(code), vec![
+            "fn main: main takes no parameters, or one for the command line arguments",
+            "fn main: `env` reads from `process`, not `stdin`",
+            "fn main: `process` can only be read by `env`",
+            "fn main: file handler `process` is not opened for writing",
+        ]);
+        assert!(errors_of("fn main(args) { env@process >> HOME; writelist@stdout << cons(HOME, args); 0 }").is_empty());
+    }
+}
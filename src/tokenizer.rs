@@ -1,4 +1,5 @@
 use std::iter::FromIterator;
+use num_bigint::BigInt;
 use phf::phf_map;
 use log::{info,debug};
 
@@ -36,6 +37,8 @@ pub enum Token {
 
 
     Integer64(i64),
+    // A literal which doesn't fit in an i64
+    BigInteger(BigInt),
     LiteralString(String),
     LiteralTrue,
     LiteralFalse,
@@ -46,23 +49,32 @@ pub enum Token {
 }
 use crate::tokenizer::Token::*;
 
-use crate::builtin::ARITHMETIC_OPERATORS;
+use crate::builtin::{ARITHMETIC_OPERATORS, LOGICAL_OPERATORS};
 
 pub fn convert_source_to_tokens(code: &str) -> Vec<Token> {
+    convert_source_to_tokens_with_lines(code).0
+}
+
+// Also returns the line (starting from 1) of every token
+pub fn convert_source_to_tokens_with_lines(code: &str) -> (Vec<Token>, Vec<usize>) {
     let mut result = Vec::new();
+    let mut lines = Vec::new();
+    let mut line = 1;
     let mut pos = 0;
     let code_vec:Vec<char> = code.chars().collect();
-    while pos < code.len() {
+    while pos < code_vec.len() {
         let len = skip_line_comment(&code_vec, pos);
         pos += len;
         let (len, token) = read_next_token(&code_vec, pos);
         debug!("Got token:{:?}", &token);
-        pos += len;
         if token != Token::SpaceToken {
             result.push(token);
+            lines.push(line);
         }
+        line += code_vec[pos..pos + len].iter().filter(|c| **c == '\n').count();
+        pos += len;
     }
-    result
+    (result, lines)
 }
 
 fn skip_line_comment(code: &Vec<char>, pos: usize) -> usize {
@@ -145,7 +157,7 @@ fn read_next_integer(code: &Vec<char>, pos: usize) -> (usize, Option<Token>) {
     let mut prim_len = 0;
     let mut result: Vec<u8> = Vec::new();
 
-    // TODO no overflow detect
+    // Digits too many for an i64 make a BigInteger
     while pos + prim_len < code.len() && code[pos+prim_len].is_digit(10) {
         result.push(code[pos+prim_len] as u8);
         prim_len += 1;
@@ -158,8 +170,13 @@ fn read_next_integer(code: &Vec<char>, pos: usize) -> (usize, Option<Token>) {
         assert_eq!(result.len(), 1); //TODO hex support
         return (1, Some(Token::Integer64(0)));
     }
-    let num:i64 = atoi::atoi(&result).unwrap();
-    return (result.len(), Some(Token::Integer64(num)));
+    match atoi::atoi::<i64>(&result) {
+        Some(num) => (result.len(), Some(Token::Integer64(num))),
+        None => {
+            let num = BigInt::parse_bytes(&result, 10).unwrap();
+            (result.len(), Some(Token::BigInteger(num)))
+        }
+    }
 }
 
 
@@ -200,17 +217,15 @@ fn is_valid_identifier_first_letter(c:char) -> bool {
 }
 fn read_next_arithmetic_operator(code: &Vec<char>, pos: usize) -> (usize, Option<Token>) {
     // Note the order of them, make sure <= is earlier than <
-    for op in ARITHMETIC_OPERATORS {
+    for op in ARITHMETIC_OPERATORS.iter().chain(LOGICAL_OPERATORS) {
         if remained_chars(code, pos) < op.len() {
             continue;
         }
         let keyword_len = op.len();
         let code_head = &code[pos.. pos + keyword_len];
 
-        let code_head_str: String = code_head.iter().collect();
-        assert_eq!(code_head_str.len(), keyword_len);
-
-        if code_head_str == *op {
+        // Compared char by char, the code may have other characters than ASCII
+        if code_head.iter().copied().eq(op.chars()) {
             return (keyword_len, Some( Token::IdentifierToken(String::from(*op)) ) );
         }
     }
@@ -227,10 +242,8 @@ fn get_next_token_in_map(code:&Vec<char>, pos:usize, map:&phf::Map<&'static str,
         let code_head = &code[pos.. pos + keyword_len];
 
         assert_eq!(code_head.len(), keyword_len);
-        let code_head_str: String = code_head.iter().collect();
-        assert_eq!(code_head_str.len(), keyword_len);
 
-        if code_head_str == literal {
+        if code_head.iter().copied().eq(literal.chars()) {
             return (keyword_len, Some( (*value).clone() ) );
         }
     }
@@ -303,4 +316,17 @@ fn read_next_space(c:char) -> Option<Token> {
 fn remained_chars(code: &Vec<char>, pos: usize) -> usize {
     assert!(pos < code.len());
     code.len() - pos
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigInt;
+    use crate::tokenizer::{convert_source_to_tokens, Token};
+
+    #[test]
+    fn integer_literals() {
+        let tokens = convert_source_to_tokens("9223372036854775807 9223372036854775808 0");
+        let big: BigInt = "9223372036854775808".parse().unwrap();
+        assert_eq!(tokens, vec![Token::Integer64(i64::MAX), Token::BigInteger(big), Token::Integer64(0)]);
+    }
+}
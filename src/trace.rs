@@ -0,0 +1,346 @@
+// IO is the only impure part of a program, so the IO it did determines a run completely.
+// A trace records every fopen_*, readstr and write with its outcome. Replaying it runs the program again
+// without touching the files or the stdin, reads and opens get the recorded results,
+// and the run stops as soon as the program asks for IO other than what was recorded.
+//
+// The log has one line per event after a header, the strings quoted like Rust strings:
+//
+//     ironcamel io trace 1
+//     open fopen_read fin "example/magic_number.txt"
+//     read fin "6 7 8\n"
+//     read stdin error "Read from stdin failed: ..."
+//     env HOME "/home/student"
+//     write stdout "42\n"
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+const HEADER: &str = "ironcamel io trace 1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum IoEvent {
+    Open { procedure: String, handler: String, path: String, error: Option<String> },
+    Read { handler: String, result: Result<String, String> },
+    // Even a write which failed, it's what the program asked for
+    Write { handler: String, text: String },
+    // env@process, an unset variable is recorded as empty
+    Env { name: String, value: String },
+}
+
+impl IoEvent {
+    // What the program asks for, without the outcome
+    fn request(&self) -> String {
+        match self {
+            IoEvent::Open { procedure, handler, path, .. } => format!("open {} {} {}", procedure, handler, quote(path)),
+            IoEvent::Read { handler, .. } => format!("read {}", handler),
+            IoEvent::Write { handler, text } => format!("write {} {}", handler, quote(text)),
+            IoEvent::Env { name, .. } => format!("env {}", name),
+        }
+    }
+
+    fn same_request(&self, other: &IoEvent) -> bool {
+        match (self, other) {
+            (IoEvent::Open { procedure: p1, handler: h1, path: f1, .. },
+             IoEvent::Open { procedure: p2, handler: h2, path: f2, .. }) => p1 == p2 && h1 == h2 && f1 == f2,
+            (IoEvent::Read { handler: h1, .. }, IoEvent::Read { handler: h2, .. }) => h1 == h2,
+            (IoEvent::Write { .. }, IoEvent::Write { .. }) => self == other,
+            (IoEvent::Env { name: n1, .. }, IoEvent::Env { name: n2, .. }) => n1 == n2,
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for IoEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoEvent::Open { error: Some(e), .. } => write!(f, "{} error {}", self.request(), quote(e)),
+            IoEvent::Read { result: Ok(s), .. } => write!(f, "{} {}", self.request(), quote(s)),
+            IoEvent::Read { result: Err(e), .. } => write!(f, "{} error {}", self.request(), quote(e)),
+            IoEvent::Env { value, .. } => write!(f, "{} {}", self.request(), quote(value)),
+            _ => write!(f, "{}", self.request())
+        }
+    }
+}
+
+// A clone shares the events, the caller keeps one to save or check them after the run
+#[derive(Clone, Debug)]
+pub struct IoTrace {
+    state: Rc<RefCell<TraceState>>,
+}
+
+#[derive(Debug)]
+struct TraceState {
+    replaying: bool,
+    events: Vec<IoEvent>,
+    // The next event to replay
+    next: usize,
+}
+
+impl IoTrace {
+    pub fn record() -> IoTrace {
+        IoTrace::new(false, Vec::new())
+    }
+
+    pub fn replay(events: Vec<IoEvent>) -> IoTrace {
+        IoTrace::new(true, events)
+    }
+
+    fn new(replaying: bool, events: Vec<IoEvent>) -> IoTrace {
+        IoTrace { state: Rc::new(RefCell::new(TraceState { replaying, events, next: 0 })) }
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.state.borrow().replaying
+    }
+
+    pub fn events(&self) -> Vec<IoEvent> {
+        self.state.borrow().events.clone()
+    }
+
+    pub fn to_log(&self) -> String {
+        let mut log = format!("{}\n", HEADER);
+        for event in &self.state.borrow().events {
+            log.push_str(&format!("{}\n", event));
+        }
+        log
+    }
+
+    // A trace to replay
+    pub fn from_log(log: &str) -> Result<IoTrace, String> {
+        let mut lines = log.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(String::from("not an IO trace of this version of ironcamel"));
+        }
+        let events = lines.enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| parse_event(line).map_err(|e| format!("line {}: {}", i + 2, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(IoTrace::replay(events))
+    }
+
+    // After a replay which ended without an error, every recorded event must have been asked for
+    pub fn finish(&self) -> Result<(), String> {
+        let state = self.state.borrow();
+        match state.events.get(state.next) {
+            Some(event) if state.replaying => Err(format!(
+                "replay diverged: the program ended, but the recorded run went on with `{}` (IO #{})",
+                event.request(), state.next + 1)),
+            _ => Ok(())
+        }
+    }
+
+    pub(crate) fn record_event(&self, event: IoEvent) {
+        self.state.borrow_mut().events.push(event);
+    }
+
+    // Returns the recorded outcome, or an Err if the program diverged.
+    // An open which succeeded returns None, the file itself is not needed as its reads are recorded too
+    pub(crate) fn replay_open(&self, procedure: &str, handler: &str, path: &str) -> Result<Option<String>, String> {
+        let asked = IoEvent::Open { procedure: procedure.to_owned(), handler: handler.to_owned(), path: path.to_owned(), error: None };
+        match self.replay_event(&asked)? {
+            IoEvent::Open { error, .. } => Ok(error),
+            _ => unreachable!()
+        }
+    }
+
+    pub(crate) fn replay_read(&self, handler: &str) -> Result<Result<String, String>, String> {
+        match self.replay_event(&IoEvent::Read { handler: handler.to_owned(), result: Ok(String::new()) })? {
+            IoEvent::Read { result, .. } => Ok(result),
+            _ => unreachable!()
+        }
+    }
+
+    pub(crate) fn replay_env(&self, name: &str) -> Result<String, String> {
+        match self.replay_event(&IoEvent::Env { name: name.to_owned(), value: String::new() })? {
+            IoEvent::Env { value, .. } => Ok(value),
+            _ => unreachable!()
+        }
+    }
+
+    pub(crate) fn replay_write(&self, handler: &str, text: &str) -> Result<(), String> {
+        self.replay_event(&IoEvent::Write { handler: handler.to_owned(), text: text.to_owned() }).map(|_| ())
+    }
+
+    fn replay_event(&self, asked: &IoEvent) -> Result<IoEvent, String> {
+        let mut state = self.state.borrow_mut();
+        let n = state.next + 1;
+        let recorded = match state.events.get(state.next) {
+            Some(recorded) if recorded.same_request(asked) => recorded.clone(),
+            Some(recorded) => return Err(format!(
+                "replay diverged at IO #{}: the program asks for `{}`, but the recorded run did `{}`",
+                n, asked.request(), recorded.request())),
+            None => return Err(format!(
+                "replay diverged at IO #{}: the program asks for `{}`, but the recorded run did no more IO",
+                n, asked.request()))
+        };
+        state.next += 1;
+        Ok(recorded)
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Bare words and quoted strings, separated by spaces
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ' ' {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            words.push(unquote(&mut chars)?);
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() && c != ' ' {
+                word.push(c);
+                chars.next();
+            }
+            words.push(word);
+        }
+    }
+    Ok(words)
+}
+
+// After the opening quote
+fn unquote(chars: &mut impl Iterator<Item = char>) -> Result<String, String> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            None => return Err(String::from("unterminated string")),
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('n') => s.push('\n'),
+                Some('r') => s.push('\r'),
+                Some('t') => s.push('\t'),
+                Some('u') => {
+                    let code: String = chars.by_ref().take_while(|c| *c != '}').skip(1).collect();
+                    let c = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32)
+                        .ok_or_else(|| format!("bad escape \\u{{{}}}", code))?;
+                    s.push(c);
+                },
+                other => return Err(format!("bad escape \\{}", other.map(String::from).unwrap_or_default()))
+            },
+            Some(c) => s.push(c)
+        }
+    }
+}
+
+fn parse_event(line: &str) -> Result<IoEvent, String> {
+    let words = split_words(line)?;
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let event = match words[..] {
+        ["open", procedure, handler, path] => IoEvent::Open {
+            procedure: procedure.to_owned(), handler: handler.to_owned(), path: path.to_owned(), error: None },
+        ["open", procedure, handler, path, "error", e] => IoEvent::Open {
+            procedure: procedure.to_owned(), handler: handler.to_owned(), path: path.to_owned(), error: Some(e.to_owned()) },
+        ["read", handler, s] => IoEvent::Read { handler: handler.to_owned(), result: Ok(s.to_owned()) },
+        ["read", handler, "error", e] => IoEvent::Read { handler: handler.to_owned(), result: Err(e.to_owned()) },
+        ["write", handler, text] => IoEvent::Write { handler: handler.to_owned(), text: text.to_owned() },
+        ["env", name, value] => IoEvent::Env { name: name.to_owned(), value: value.to_owned() },
+        _ => return Err(format!("unknown event `{}`", line))
+    };
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::{eval_with_io, EvalOptions};
+    use crate::io::MemoryIo;
+    use crate::pipeline::test_support::build;
+    use crate::trace::{IoEvent, IoTrace};
+
+    const PROGRAM: &str = "fn main() {
+        readstr@stdin >> a;
+        fopen_read@fin = \"numbers\";
+        readstr@fin >> b;
+        fopen_read@missing = \"missing\";
+        writeline@stdout << +(atoi(a), atoi(b));
+        0 }";
+
+    #[test]
+    fn log_round_trip() {
+        let trace = IoTrace::record();
+        let events = [
+            IoEvent::Open { procedure: String::from("fopen_read"), handler: String::from("fin"),
+                            path: String::from("a \"b\".txt"), error: Some(String::from("no")) },
+            IoEvent::Read { handler: String::from("fin"), result: Ok(String::from("x\\y\n\t\u{1}é")) },
+            IoEvent::Read { handler: String::from("stdin"), result: Err(String::from("failed")) },
+            IoEvent::Write { handler: String::from("stdout"), text: String::new() },
+            IoEvent::Env { name: String::from("HOME"), value: String::from("/home/a b") },
+        ];
+        for event in events.iter().cloned() {
+            trace.record_event(event);
+        }
+        assert_eq!(IoTrace::from_log(&trace.to_log()).unwrap().events(), events);
+        assert!(IoTrace::from_log("ironcamel io trace 1\nread stdin \"x").unwrap_err().starts_with("line 2:"));
+        assert!(IoTrace::from_log("read stdin \"x\"").is_err());
+    }
+
+    #[test]
+    fn replay_without_the_files() {
+        let trace = IoTrace::record();
+        let options = EvalOptions { trace: Some(trace.clone()), ..EvalOptions::default() };
+        let io = MemoryIo::new().with_stdin("40\n").with_file("numbers", "2");
+        let recorded = eval_with_io(&build(PROGRAM), Box::new(io.clone()), &options).unwrap_err();
+        assert!(recorded.message.starts_with("Can't open missing"));
+        let log = trace.to_log();
+
+        for vm in [false, true] {
+            let trace = IoTrace::from_log(&log).unwrap();
+            let options = EvalOptions { trace: Some(trace.clone()), vm, ..EvalOptions::default() };
+            let replayed = MemoryIo::new();
+            let e = eval_with_io(&build(PROGRAM), Box::new(replayed.clone()), &options).unwrap_err();
+            assert_eq!(e.message, recorded.message);
+            assert!(trace.finish().is_ok());
+        }
+
+        // Reading the file before the stdin
+        let diverged = PROGRAM.replace("readstr@stdin >> a;\n", "").replace("readstr@fin >> b;", "readstr@fin >> b; readstr@stdin >> a;");
+        let options = EvalOptions { trace: Some(IoTrace::from_log(&log).unwrap()), ..EvalOptions::default() };
+        let e = eval_with_io(&build(&diverged), Box::new(MemoryIo::new()), &options).unwrap_err();
+        assert_eq!(e.message, "replay diverged at IO #1: the program asks for `open fopen_read fin \"numbers\"`, \
+                               but the recorded run did `read stdin`");
+    }
+
+    #[test]
+    fn replay_checks_writes_and_the_end() {
+        let trace = IoTrace::record();
+        let options = EvalOptions { trace: Some(trace.clone()), ..EvalOptions::default() };
+        let program = "fn main() { writeline@stdout << 1; writeline@stdout << 2; 0 }";
+        eval_with_io(&build(program), Box::new(MemoryIo::new()), &options).unwrap();
+        let log = trace.to_log();
+
+        let replay = |program: &str| {
+            let trace = IoTrace::from_log(&log).unwrap();
+            let options = EvalOptions { trace: Some(trace.clone()), ..EvalOptions::default() };
+            let io = MemoryIo::new();
+            eval_with_io(&build(program), Box::new(io.clone()), &options).map(|_| (io.stdout(), trace.finish()))
+        };
+        assert_eq!(replay(program).unwrap(), (String::from("1\n2\n"), Ok(())));
+        let e = replay("fn main() { writeline@stdout << 1; writeline@stdout << 3; 0 }").unwrap_err();
+        assert_eq!(e.message, "replay diverged at IO #2: the program asks for `write stdout \"3\\n\"`, \
+                               but the recorded run did `write stdout \"2\\n\"`");
+        let (_, end) = replay("fn main() { writeline@stdout << 1; 0 }").unwrap();
+        assert_eq!(end.unwrap_err(), "replay diverged: the program ended, but the recorded run went on with \
+                                      `write stdout \"2\\n\"` (IO #2)");
+    }
+}
@@ -0,0 +1,485 @@
+// Runtime values. The AST only describes the program, everything the interpreter computes is a Value.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+use num_bigint::BigInt;
+use crate::env::Env;
+use crate::lower::{ClosureId, FunctionId, ThunkId};
+
+#[derive(Clone)]
+pub enum Value {
+    Int(i64),
+    // Only integers which don't fit in an Int, see Value::integer
+    BigInt(Rc<BigInt>),
+    Bool(bool),
+    Str(Rc<str>),
+    List(Rc<IroncamelLinkedList>),
+    // A global function used as a value
+    Function(FunctionId),
+    Builtin(&'static str),
+    Closure(ClosureId, Env),
+    // Only in the lazy mode: a delayed expression, and a list whose tail is one
+    Thunk(Rc<Thunk>),
+    LazyCons(Rc<LazyCons>),
+}
+
+impl Value {
+    pub fn empty_list() -> Value {
+        Value::List(Rc::new(IroncamelLinkedList::build_empty_list()))
+    }
+
+    pub fn list(values: &[Value]) -> Value {
+        Value::List(Rc::new(IroncamelLinkedList::build_list(values)))
+    }
+
+    pub fn str(s: &str) -> Value {
+        Value::Str(Rc::from(s))
+    }
+
+    // An Int whenever it fits, so the same number is never both an Int and a BigInt
+    pub fn integer(x: BigInt) -> Value {
+        match i64::try_from(&x) {
+            Ok(x) => Value::Int(x),
+            Err(_) => Value::BigInt(Rc::new(x))
+        }
+    }
+
+    // Either kind of integer as a BigInt, the slow path of the arithmetic
+    pub(crate) fn to_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::Int(x) => Some(BigInt::from(*x)),
+            Value::BigInt(x) => Some(BigInt::clone(x)),
+            _ => None
+        }
+    }
+
+    // A short form for stack traces, long lists and strings are cut
+    pub fn summary(&self) -> String {
+        match self {
+            Value::Str(s) if s.chars().count() > SUMMARY_STR_LEN => {
+                let head: String = s.chars().take(SUMMARY_STR_LEN).collect();
+                format!("{:?}...", head)
+            },
+            Value::List(l) => {
+                let mut items: Vec<String> = l.iter().take(SUMMARY_LIST_LEN).map(|v| v.summary()).collect();
+                if l.len > SUMMARY_LIST_LEN {
+                    items.push(format!("... {} more", l.len - SUMMARY_LIST_LEN));
+                }
+                format!("list({})", items.join(", "))
+            },
+            Value::Thunk(t) => match t.value() {
+                Some(v) => v.summary(),
+                None => String::from("<thunk>")
+            },
+            Value::LazyCons(c) => format!("cons({}, {})", c.head.summary(), c.tail.summary()),
+            _ => self.to_string()
+        }
+    }
+}
+
+pub struct Thunk {
+    state: RefCell<ThunkState>
+}
+
+enum ThunkState {
+    Delayed(ThunkId, Env),
+    // Never another thunk
+    Forced(Value),
+}
+
+impl Thunk {
+    pub fn new(id: ThunkId, env: Env) -> Thunk {
+        Thunk { state: RefCell::new(ThunkState::Delayed(id, env)) }
+    }
+
+    // None if not evaluated yet
+    pub fn value(&self) -> Option<Value> {
+        match &*self.state.borrow() {
+            ThunkState::Forced(v) => Some(v.clone()),
+            ThunkState::Delayed(..) => None
+        }
+    }
+
+    pub fn delayed(&self) -> Option<(ThunkId, Env)> {
+        match &*self.state.borrow() {
+            ThunkState::Delayed(id, env) => Some((*id, env.clone())),
+            ThunkState::Forced(_) => None
+        }
+    }
+
+    // Drops the environment, which may be the last reference to a lot of other thunks
+    pub fn set(&self, value: Value) {
+        *self.state.borrow_mut() = ThunkState::Forced(value);
+    }
+}
+
+// cons(head, tail) when the tail is not evaluated yet, so its length is unknown
+pub struct LazyCons {
+    pub head: Value,
+    pub tail: Value
+}
+
+// Like the strict list, a long lazy list is a long chain of tails and forced thunks
+impl Drop for LazyCons {
+    fn drop(&mut self) {
+        let mut next = std::mem::replace(&mut self.tail, Value::Bool(false));
+        loop {
+            next = match next {
+                Value::LazyCons(c) => match Rc::try_unwrap(c) {
+                    Ok(mut c) => std::mem::replace(&mut c.tail, Value::Bool(false)),
+                    Err(_) => break
+                },
+                Value::Thunk(t) => match Rc::try_unwrap(t) {
+                    Ok(t) => match t.state.into_inner() {
+                        ThunkState::Forced(v) => v,
+                        ThunkState::Delayed(..) => break
+                    },
+                    Err(_) => break
+                },
+                _ => break
+            };
+        }
+    }
+}
+
+// Conversions for Rust hosts, see embed.rs
+impl From<i64> for Value {
+    fn from(x: i64) -> Value {
+        Value::Int(x)
+    }
+}
+
+impl From<BigInt> for Value {
+    fn from(x: BigInt) -> Value {
+        Value::integer(x)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(x: bool) -> Value {
+        Value::Bool(x)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::str(s)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::str(&s)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        let items: Vec<Value> = items.into_iter().map(Into::into).collect();
+        Value::list(&items)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConversionError {
+    pub expected: &'static str,
+    // Value::summary of what was found
+    pub found: String
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expect {}, got {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+fn conversion_error(expected: &'static str, found: &Value) -> ConversionError {
+    ConversionError { expected, found: found.summary() }
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = ConversionError;
+    fn try_from(value: Value) -> Result<i64, ConversionError> {
+        match value {
+            Value::Int(x) => Ok(x),
+            _ => Err(conversion_error("an integer", &value))
+        }
+    }
+}
+
+impl TryFrom<Value> for BigInt {
+    type Error = ConversionError;
+    fn try_from(value: Value) -> Result<BigInt, ConversionError> {
+        value.to_bigint().ok_or_else(|| conversion_error("an integer", &value))
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = ConversionError;
+    fn try_from(value: Value) -> Result<bool, ConversionError> {
+        match value {
+            Value::Bool(x) => Ok(x),
+            _ => Err(conversion_error("a boolean value", &value))
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = ConversionError;
+    fn try_from(value: Value) -> Result<String, ConversionError> {
+        match value {
+            Value::Str(s) => Ok(s.to_string()),
+            _ => Err(conversion_error("a String", &value))
+        }
+    }
+}
+
+impl<T: TryFrom<Value, Error = ConversionError>> TryFrom<Value> for Vec<T> {
+    type Error = ConversionError;
+    fn try_from(value: Value) -> Result<Vec<T>, ConversionError> {
+        match value {
+            Value::List(l) => l.iter().map(|v| T::try_from(v.clone())).collect(),
+            _ => Err(conversion_error("a list", &value))
+        }
+    }
+}
+
+const SUMMARY_STR_LEN: usize = 32;
+const SUMMARY_LIST_LEN: usize = 5;
+
+// Structural equality for values which can be written by IO, callables are never equal
+pub fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Int(x), Value::Int(y)) => x == y,
+        (Value::BigInt(x), Value::BigInt(y)) => x == y,
+        (Value::Bool(x), Value::Bool(y)) => x == y,
+        (Value::Str(x), Value::Str(y)) => x == y,
+        (Value::List(x), Value::List(y)) => {
+            x.len == y.len && x.iter().zip(y.iter()).all(|(p, q)| values_equal(p, q))
+        },
+        _ => false
+    }
+}
+
+// The order of <, >, <= and >=: integers by value, strings and lists lexicographically
+// (strings by unicode scalar values). Anything else, or values of two different kinds, can't be ordered
+pub fn compare_values(a: &Value, b: &Value) -> Result<Ordering, String> {
+    match (a, b) {
+        (Value::Int(x), Value::Int(y)) => Ok(x.cmp(y)),
+        (Value::Int(_) | Value::BigInt(_), Value::Int(_) | Value::BigInt(_)) => Ok(a.to_bigint().cmp(&b.to_bigint())),
+        (Value::Str(x), Value::Str(y)) => Ok(x.cmp(y)),
+        (Value::List(x), Value::List(y)) => {
+            for (p, q) in x.iter().zip(y.iter()) {
+                match compare_values(p, q)? {
+                    Ordering::Equal => (),
+                    unequal => return Ok(unequal)
+                }
+            }
+            Ok(x.len.cmp(&y.len))
+        },
+        _ => Err(format!("Can't compare {} and {}", a.summary(), b.summary()))
+    }
+}
+
+// How a value is shown in error messages, strings are quoted
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(x) => write!(f, "{}", x),
+            Value::BigInt(x) => write!(f, "{}", x),
+            Value::Bool(x) => write!(f, "{}", x),
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::List(l) => {
+                let items: Vec<String> = l.iter().map(|v| v.to_string()).collect();
+                write!(f, "list({})", items.join(", "))
+            },
+            Value::Function(id) => write!(f, "<function #{}>", id),
+            Value::Builtin(name) => write!(f, "<builtin {}>", name),
+            Value::Closure(id, _) => write!(f, "<closure #{}>", id),
+            Value::Thunk(t) => match t.value() {
+                Some(v) => write!(f, "{}", v),
+                None => write!(f, "<thunk>")
+            },
+            Value::LazyCons(c) => write!(f, "cons({}, {})", c.head, c.tail),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+
+pub struct IroncamelLinkedList {
+    // None only for the empty list
+    value: Option<Value>,
+    pub(crate) len: usize, // Allows us to calculate list size with O(1) cost
+    next: Option<Rc<IroncamelLinkedList>>
+}
+
+impl IroncamelLinkedList {
+    pub(crate) fn build_empty_list() -> IroncamelLinkedList {
+        IroncamelLinkedList {
+            value: None,
+            len: 0,
+            next: None
+        }
+    }
+    pub fn build_list(values: &[Value]) -> IroncamelLinkedList {
+        let mut list = IroncamelLinkedList::build_empty_list();
+        for v in values.iter().rev() {
+            list = IroncamelLinkedList::cons(v.clone(), &Rc::new(list));
+        }
+        list
+    }
+    pub fn build(value: Value) -> IroncamelLinkedList {
+        IroncamelLinkedList {
+            value: Some(value),
+            len: 1,
+            next: None
+        }
+    }
+    pub fn cons(value: Value, tail: &Rc<IroncamelLinkedList>) -> IroncamelLinkedList {
+        IroncamelLinkedList {
+            value: Some(value),
+            len: 1 + tail.len,
+            next: if tail.len == 0 { None } else { Some(Rc::clone(tail)) },
+        }
+    }
+    pub fn hd(&self) -> &Value {
+        match &self.value {
+            Some(v) => v,
+            None => panic!("hd of an empty list")
+        }
+    }
+    pub fn tl(&self) -> Option<Rc<IroncamelLinkedList>> {
+        assert!(self.len > 0);
+        if self.len > 1 {
+            let tail = self.next.as_ref().unwrap();
+            Some(Rc::clone(tail))
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> IroncamelLinkedListIter<'_> {
+        IroncamelLinkedListIter { current: Some(self) }
+    }
+
+    pub fn as_vector_i64(&self) -> Vec<i64> {
+        self.iter().map(|v| match v {
+            Value::Int(x) => *x,
+            _ => panic!("Expect an integer")
+        }).collect()
+    }
+}
+
+pub struct IroncamelLinkedListIter<'a> {
+    current: Option<&'a IroncamelLinkedList>
+}
+
+impl<'a> Iterator for IroncamelLinkedListIter<'a> {
+    type Item = &'a Value;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.current?;
+        self.current = node.next.as_deref();
+        node.value.as_ref()
+    }
+}
+
+// The derived drop is recursive, which would overflow the stack for a long list
+impl Drop for IroncamelLinkedList {
+    fn drop(&mut self) {
+        let mut next = self.next.take();
+        while let Some(node) = next {
+            next = match Rc::try_unwrap(node) {
+                Ok(mut n) => n.next.take(),
+                Err(_) => None // Still shared by another list
+            };
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cmp::Ordering;
+    use crate::value::{compare_values, IroncamelLinkedList, Value, values_equal};
+    use num_bigint::BigInt;
+
+    #[test]
+    fn build_linkedlist() {
+        let list = IroncamelLinkedList::build(Value::Int(5));
+        assert_eq!(list.len, 1);
+        assert_eq!(IroncamelLinkedList::build_empty_list().iter().count(), 0);
+    }
+
+    #[test]
+    fn insert_to_list() {
+        let l1 = IroncamelLinkedList::build(Value::Int(5));
+        assert_eq!(l1.as_vector_i64(), vec![5]);
+        let l2 = IroncamelLinkedList::cons(Value::Int(42), &Rc::new(l1));
+        assert_eq!(l2.as_vector_i64(), vec![42, 5]);
+    }
+
+    #[test]
+    fn car_cdr_list() {
+        let l1 = IroncamelLinkedList::build(Value::Int(5));
+        assert_eq!(l1.as_vector_i64(), vec![5]);
+        let l2 = IroncamelLinkedList::cons(Value::Int(42), &Rc::new(l1));
+        assert_eq!(l2.as_vector_i64(), vec![42, 5]);
+
+        assert!(matches!(l2.hd(), Value::Int(42)));
+        match l2.tl() {
+            Some(l3) => assert_eq!(l3.as_vector_i64(), vec![5]),
+            None => panic!("Expect a tail")
+        };
+    }
+
+    #[test]
+    fn compare_lists() {
+        let l1 = Value::list(&[Value::Int(1), Value::str("a")]);
+        let l2 = Value::list(&[Value::Int(1), Value::str("a")]);
+        let l3 = Value::list(&[Value::Int(1)]);
+        assert!(values_equal(&l1, &l2));
+        assert!(!values_equal(&l1, &l3));
+        assert!(!values_equal(&l1, &Value::Int(1)));
+        assert_eq!(l1.to_string(), "list(1, \"a\")");
+
+        assert_eq!(compare_values(&l3, &l1), Ok(Ordering::Less));
+        assert_eq!(compare_values(&Value::str("é"), &Value::str("z")), Ok(Ordering::Greater));
+        let l4 = Value::list(&[Value::Int(2), Value::Bool(true)]);
+        assert_eq!(compare_values(&l1, &l4), Ok(Ordering::Less));
+        assert_eq!(compare_values(&l4, &l1), Ok(Ordering::Greater));
+        assert_eq!(compare_values(&l1, &Value::list(&[Value::Int(1), Value::Int(2)])),
+                   Err(String::from("Can't compare \"a\" and 2")));
+        assert!(compare_values(&Value::Bool(false), &Value::Bool(true)).is_err());
+    }
+
+    #[test]
+    fn normalize_integers() {
+        let big = BigInt::from(i64::MAX) + 1u32;
+        assert!(matches!(Value::integer(big.clone() - 1u32), Value::Int(i64::MAX)));
+        assert!(matches!(Value::integer(big.clone()), Value::BigInt(_)));
+        assert!(values_equal(&Value::integer(big.clone()), &Value::from(big.clone())));
+        assert_eq!(compare_values(&Value::Int(i64::MAX), &Value::integer(big.clone())), Ok(Ordering::Less));
+        assert_eq!(compare_values(&Value::integer(-big), &Value::Int(i64::MIN)), Ok(Ordering::Equal));
+        assert_eq!(Value::integer(BigInt::from(u64::MAX)).to_string(), "18446744073709551615");
+    }
+
+    #[test]
+    fn summarize_long_values() {
+        let long: Vec<Value> = (0..100).map(Value::Int).collect();
+        assert_eq!(Value::list(&long).summary(), "list(0, 1, 2, 3, 4, ... 95 more)");
+        assert_eq!(Value::list(&long[..2]).summary(), "list(0, 1)");
+        assert_eq!(Value::str(&"x".repeat(40)).summary(), format!("{:?}...", "x".repeat(32)));
+    }
+}
@@ -0,0 +1,306 @@
+// The virtual machine running a BytecodeProgram, see bytecode.rs for what the ops do.
+// Like the interpreter it never recurses on the Rust stack: the values of every running call share one vector,
+// and the calls themselves are kept in another one.
+// Errors have the same messages and stack traces as in the interpreter.
+
+use crate::builtin;
+use crate::bytecode::{BytecodeProgram, Capture, IoOp, Op, Proto};
+use crate::env::Env;
+use crate::interpreter::{call_frame, call_trace, open_file, GlobalState, Limit, RuntimeError, StackFrame};
+use crate::lower::SiteId;
+use crate::value::Value;
+
+struct CallFrame<'a> {
+    proto: &'a Proto,
+    // The next op, only up to date while another call runs
+    ip: usize,
+    // values[base] is the first parameter
+    base: usize,
+    // Where values is cut when the call returns, below base if the callee was a value on the stack
+    bottom: usize,
+    // Only for closures
+    upvalues: Option<Env>,
+    // Where it was called, for stack traces. Unused for the entry
+    site: SiteId,
+}
+
+enum Target<'a> {
+    Proto(&'a Proto, Option<Env>),
+    Builtin(&'static str),
+}
+
+struct Machine<'a> {
+    program: &'a BytecodeProgram,
+    global: &'a mut GlobalState,
+    values: Vec<Value>,
+    // The entry (main or a test) at the bottom, it's not a call in stack traces
+    frames: Vec<CallFrame<'a>>,
+    // Steps left before Limits.max_steps is exceeded, kept here rather than in Usage as it's checked on every call
+    fuel: u64,
+}
+
+// `name` is main, the test or the function called by the host at the bottom of a stack trace.
+// The arguments are only given to a function, their number is checked by the caller
+pub(crate) fn execute_entry(global: &mut GlobalState, program: &BytecodeProgram, entry: &Proto, name: &str,
+                            arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+    let fuel = match global.limits.max_steps {
+        Some(max) => max.saturating_sub(global.usage.steps.get()),
+        None => u64::MAX
+    };
+    let mut machine = Machine { program, global, values: arguments, frames: Vec::new(), fuel };
+    let result = machine.run(entry);
+    let steps = &machine.global.usage.steps;
+    steps.set(steps.get() + (fuel - machine.fuel));
+    result.map_err(|mut e| {
+        e.stack.push(StackFrame::Entry { name: name.to_owned(), location: entry.location.to_owned() });
+        e
+    })
+}
+
+impl<'a> Machine<'a> {
+    fn run(&mut self, entry: &'a Proto) -> Result<Value, RuntimeError> {
+        let program = self.program;
+        // The slots of let bindings are filled with false until they are bound, after the arguments if any
+        self.values.resize(entry.locals, Value::Bool(false));
+        self.frames.push(CallFrame { proto: entry, ip: 0, base: 0, bottom: 0, upvalues: None, site: 0 });
+        // The running call, kept out of self.frames
+        let mut code: &'a [Op] = &entry.code;
+        let mut ip = 0;
+        let mut base = 0;
+        loop {
+            let op = &code[ip];
+            ip += 1;
+            match *op {
+                Op::Const(i) => self.values.push(program.constants[i].clone()),
+                Op::Local(slot) => {
+                    let value = self.values[base + slot].clone();
+                    self.values.push(value);
+                },
+                Op::Upvalue(i) => {
+                    let value = self.upvalue(i).clone();
+                    self.values.push(value);
+                },
+                Op::Function(id) => self.values.push(Value::Function(id)),
+                Op::Builtin(name) => self.values.push(Value::Builtin(name)),
+                Op::Closure(id) => {
+                    let captures = &program.closures[id].captures;
+                    let mut upvalues = Env::new(None, captures.len());
+                    for capture in captures {
+                        let value = match *capture {
+                            Capture::Local(slot) => &self.values[base + slot],
+                            Capture::Upvalue(i) => self.upvalue(i)
+                        };
+                        upvalues.push(value.clone());
+                    }
+                    self.values.push(Value::Closure(id, upvalues));
+                },
+                Op::SetLocal(slot) => {
+                    let value = self.values.pop().unwrap();
+                    self.values[base + slot] = value;
+                },
+                Op::JumpIfFalse(target) => match self.values.pop().unwrap() {
+                    Value::Bool(true) => (),
+                    Value::Bool(false) => ip = target,
+                    value => return Err(self.error(format!("Expect a boolean value, got {}", value.summary())))
+                },
+                Op::Jump(target) => ip = target,
+                Op::CheckCallable => {
+                    let value = self.values.last().unwrap();
+                    if self.target_of(value).is_none() {
+                        return Err(self.error(format!("Expect a callable object, got {}", value.summary())));
+                    }
+                },
+                Op::CallBuiltin(name, argc, site) => self.call_builtin(name, argc, site)?,
+                Op::CallFunction(id, argc, site) => {
+                    self.frames.last_mut().unwrap().ip = ip;
+                    let bottom = self.values.len() - argc;
+                    self.enter(&program.functions[id], None, argc, bottom, site)?;
+                    (code, ip, base) = self.resume();
+                },
+                Op::Call(argc, site) => {
+                    let bottom = self.values.len() - argc - 1;
+                    match self.target_of(&self.values[bottom]).unwrap() {
+                        Target::Builtin(name) => {
+                            self.call_builtin(name, argc, site)?;
+                            // The callee is still below the result
+                            self.values.swap_remove(bottom);
+                        },
+                        Target::Proto(proto, upvalues) => {
+                            self.frames.last_mut().unwrap().ip = ip;
+                            self.enter(proto, upvalues, argc, bottom, site)?;
+                            (code, ip, base) = self.resume();
+                        }
+                    }
+                },
+                Op::TailCallFunction(id, argc, site) => {
+                    self.replace(&program.functions[id], None, argc, site)?;
+                    (code, ip, base) = self.resume();
+                },
+                Op::TailCall(argc, site) => {
+                    let callee = self.values.len() - argc - 1;
+                    match self.target_of(&self.values[callee]).unwrap() {
+                        Target::Builtin(name) => {
+                            self.call_builtin(name, argc, site)?;
+                            let value = self.values.pop().unwrap();
+                            match self.leave(value) {
+                                Some(value) => return Ok(value),
+                                None => (code, ip, base) = self.resume()
+                            }
+                        },
+                        Target::Proto(proto, upvalues) => {
+                            self.replace(proto, upvalues, argc, site)?;
+                            (code, ip, base) = self.resume();
+                        }
+                    }
+                },
+                Op::Return => {
+                    let value = self.values.pop().unwrap();
+                    match self.leave(value) {
+                        Some(value) => return Ok(value),
+                        None => (code, ip, base) = self.resume()
+                    }
+                },
+                Op::Io(i) => self.perform_io(&program.io[i])?,
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn step(&mut self) -> Result<(), RuntimeError> {
+        self.burn_fuel().map_err(|limit| self.limit_error(limit))
+    }
+
+    #[inline(always)]
+    fn burn_fuel(&mut self) -> Result<(), Limit> {
+        if self.fuel == 0 {
+            return Err(Limit::Steps);
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+
+    // Where the running call goes on
+    fn resume(&self) -> (&'a [Op], usize, usize) {
+        let frame = self.frames.last().unwrap();
+        (&frame.proto.code, frame.ip, frame.base)
+    }
+
+    fn upvalue(&self, i: usize) -> &Value {
+        match &self.frames.last().unwrap().upvalues {
+            Some(upvalues) => upvalues.get(0, i),
+            None => panic!("Not a closure, no upvalue {}", i)
+        }
+    }
+
+    fn target_of(&self, value: &Value) -> Option<Target<'a>> {
+        let program = self.program;
+        match value {
+            Value::Function(id) => Some(Target::Proto(&program.functions[*id], None)),
+            Value::Closure(id, upvalues) => Some(Target::Proto(&program.closures[*id], Some(upvalues.clone()))),
+            Value::Builtin(name) => Some(Target::Builtin(name)),
+            _ => None
+        }
+    }
+
+    // The arguments are the top `argc` values, the result replaces them
+    fn call_builtin(&mut self, name: &'static str, argc: usize, site: SiteId) -> Result<(), RuntimeError> {
+        let start = self.values.len() - argc;
+        let result = self.burn_fuel().map(|()| builtin::call_builtin_function(name, &self.values[start..], &self.global.natives));
+        let (message, limit) = match result {
+            Ok(Ok(value)) => match self.global.allocate(name, &value) {
+                Ok(()) => {
+                    self.values.truncate(start);
+                    self.values.push(value);
+                    return Ok(());
+                },
+                Err(limit) => (limit.message(&self.global.limits), Some(limit))
+            },
+            Ok(Err(message)) => (message, None),
+            Err(limit) => (limit.message(&self.global.limits), Some(limit))
+        };
+        let mut error = self.error(message);
+        error.stack.insert(0, call_frame(name, &self.values[start..], &self.program.sites[site]));
+        error.limit = limit;
+        Err(error)
+    }
+
+    // A call which isn't in tail position, its arguments are the top `argc` values
+    fn enter(&mut self, proto: &'a Proto, upvalues: Option<Env>, argc: usize, bottom: usize,
+             site: SiteId) -> Result<(), RuntimeError> {
+        self.step()?;
+        self.check_arity(proto, argc)?;
+        if self.frames.len() > self.global.limits.max_depth {
+            return Err(self.limit_error(Limit::Depth));
+        }
+        let base = self.values.len() - argc;
+        self.values.resize(base + proto.locals, Value::Bool(false));
+        self.frames.push(CallFrame { proto, ip: 0, base, bottom, upvalues, site });
+        Ok(())
+    }
+
+    // A tail call takes over the frame of the running call
+    fn replace(&mut self, proto: &'a Proto, upvalues: Option<Env>, argc: usize, site: SiteId) -> Result<(), RuntimeError> {
+        self.step()?;
+        self.check_arity(proto, argc)?;
+        let frame = self.frames.last_mut().unwrap();
+        let start = self.values.len() - argc;
+        self.values.drain(frame.base..start);
+        self.values.resize(frame.base + proto.locals, Value::Bool(false));
+        frame.proto = proto;
+        frame.ip = 0;
+        frame.upvalues = upvalues;
+        frame.site = site;
+        Ok(())
+    }
+
+    // Only possible through a variable, direct calls are checked before execution
+    fn check_arity(&self, proto: &Proto, argc: usize) -> Result<(), RuntimeError> {
+        if proto.arity != argc {
+            return Err(self.error(format!("{} expects {} arguments, got {}", proto.name, proto.arity, argc)));
+        }
+        Ok(())
+    }
+
+    // Some(value) if it was the entry which returned
+    fn leave(&mut self, value: Value) -> Option<Value> {
+        let frame = self.frames.pop().unwrap();
+        self.values.truncate(frame.bottom);
+        if self.frames.is_empty() {
+            return Some(value);
+        }
+        self.values.push(value);
+        None
+    }
+
+    fn perform_io(&mut self, io: &IoOp) -> Result<(), RuntimeError> {
+        match io {
+            IoOp::Read { procedure, file_handler, variable } => {
+                let value = builtin::perform_read(procedure, file_handler, variable, self.global).map_err(|m| self.error(m))?;
+                self.values.push(value);
+            },
+            IoOp::Write { procedure, file_handler } => {
+                let value = self.values.pop().unwrap();
+                builtin::perform_write(procedure, file_handler, &value, self.global).map_err(|m| self.error(m))?;
+            },
+            IoOp::FileOpen(fo) => open_file(self.global, fo)?
+        }
+        Ok(())
+    }
+
+    // The calls still running, innermost first, are the stack trace
+    #[cold]
+    fn error(&self, message: String) -> RuntimeError {
+        let calls: Vec<&CallFrame> = self.frames[1..].iter().rev().collect();
+        let stack = call_trace(&calls, |frame| {
+            call_frame(&frame.proto.name, &self.values[frame.base..frame.base + frame.proto.arity], &self.program.sites[frame.site])
+        });
+        RuntimeError { message, stack, limit: None }
+    }
+
+    #[cold]
+    fn limit_error(&self, limit: Limit) -> RuntimeError {
+        let mut error = self.error(limit.message(&self.global.limits));
+        error.limit = Some(limit);
+        error
+    }
+}
@@ -0,0 +1,196 @@
+// Golden-output tests: every example/*.icml is executed in-process and compared with
+// example/<name>.expected, which holds its stdout and every file it writes.
+//
+// Optional fixtures next to the example:
+//   <name>.stdin    fed to stdin@
+//   <name>.include  libraries to include, one path per line (default: core and stdlib)
+//   <name>.flags    command line flags changing the evaluation, one per line (--lazy and the limits),
+//                   and `-- a b` for the arguments of main
+// A runtime error is recorded with its stack trace after the output, a non-zero exit status of main too.
+// Every example which isn't lazy also runs on the virtual machine (--vm), with the same expected output.
+//
+// Regenerate the expected files with
+//   IRONCAMEL_BLESS=1 cargo test --test snapshots
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use ironcamel::interpreter::EvalOptions;
+use ironcamel::parser::{ProgramAST, StatementAST};
+use ironcamel::pipeline::{PipelineOptions, SourceFile};
+use ironcamel::value::Value;
+
+const EXAMPLE_DIR: &str = "example";
+const DEFAULT_INCLUDES: [&str; 2] = ["include/core.icml", "include/stdlib.icml"];
+const BLESS_ENV: &str = "IRONCAMEL_BLESS";
+
+// stdout@ of the program under test
+#[derive(Clone, Default)]
+struct CapturedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn read_includes(example: &Path) -> Vec<String> {
+    let include_list = example.with_extension("include");
+    match fs::read_to_string(&include_list) {
+        Ok(s) => s.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => DEFAULT_INCLUDES.iter().map(|s| s.to_string()).collect()
+    }
+}
+
+fn read_options(example: &Path) -> EvalOptions {
+    let mut options = EvalOptions::default();
+    let flags = fs::read_to_string(example.with_extension("flags")).unwrap_or_default();
+    for flag in flags.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        // The arguments of main
+        if let Some(args) = flag.strip_prefix("--").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            options.args = args.split_whitespace().map(String::from).collect();
+            continue;
+        }
+        let (name, value) = flag.split_once('=').unwrap_or((flag, ""));
+        let number = || value.parse().unwrap_or_else(|_| panic!("{}: {} expects a number", example.display(), name));
+        match name {
+            "--lazy" => options.lazy = true,
+            "--max-depth" => options.limits.max_depth = number() as usize,
+            "--max-steps" => options.limits.max_steps = Some(number()),
+            "--max-list-length" => options.limits.max_list_length = Some(number() as usize),
+            "--max-memory" => options.limits.max_memory = Some(number()),
+            _ => panic!("{}: unsupported flag {}", example.display(), flag)
+        }
+    }
+    options
+}
+
+fn build_program(example: &Path) -> ProgramAST {
+    let mut source_vec = Vec::new();
+    for lib in read_includes(example) {
+        let code = fs::read_to_string(&lib)
+            .unwrap_or_else(|e| panic!("Read lib {} failed: {}", lib, e));
+        source_vec.push(SourceFile { path: lib, code, is_library: true });
+    }
+    let code = fs::read_to_string(example).unwrap();
+    source_vec.push(SourceFile { path: example.display().to_string(), code, is_library: false });
+    ironcamel::pipeline::build_program(&source_vec, &PipelineOptions::default())
+        .unwrap_or_else(|errors| panic!("{}: {:?}", example.display(), errors))
+        .ast
+}
+
+fn written_files(ast: &ProgramAST) -> Vec<String> {
+    let main = ast.functions.iter().find(|f| f.function_name == "main").unwrap();
+    main.body.statements.iter().filter_map(|s| match s {
+        StatementAST::FileOpen(fo) if fo.impure_procedure_name == "fopen_write" => Some(fo.file_path.to_owned()),
+        _ => None
+    }).collect()
+}
+
+fn run_example(example: &Path, options: &EvalOptions) -> String {
+    let ast = build_program(example);
+    let stdin: Box<dyn std::io::BufRead> = match fs::File::open(example.with_extension("stdin")) {
+        Ok(f) => Box::new(BufReader::new(f)),
+        Err(_) => Box::new(std::io::empty())
+    };
+    let stdout = CapturedOutput::default();
+    let result = ironcamel::interpreter::eval_with_stdio_and_options(&ast, stdin, Box::new(stdout.clone()), options);
+
+    let mut snapshot = String::from("--- stdout\n");
+    snapshot.push_str(&String::from_utf8_lossy(&stdout.0.borrow()));
+    match result {
+        Err(e) => snapshot.push_str(&format!("--- error\n{}\n", e)),
+        Ok(Value::Int(status)) if status != 0 => snapshot.push_str(&format!("--- exit {}\n", status)),
+        Ok(_) => ()
+    }
+    for path in written_files(&ast) {
+        snapshot.push_str(&format!("--- file {}\n", path));
+        snapshot.push_str(&fs::read_to_string(&path).unwrap());
+    }
+    snapshot
+}
+
+// Line-based diff through the longest common subsequence, good enough for small outputs
+fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            result.push(format!("  {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            result.push(format!("- {}", a[i]));
+            i += 1;
+        } else {
+            result.push(format!("+ {}", b[j]));
+            j += 1;
+        }
+    }
+    result.join("\n")
+}
+
+fn list_examples() -> Vec<PathBuf> {
+    let mut examples: Vec<PathBuf> = fs::read_dir(EXAMPLE_DIR).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "icml"))
+        .collect();
+    examples.sort();
+    examples
+}
+
+#[test]
+fn examples_match_expected_output() {
+    let bless = std::env::var_os(BLESS_ENV).is_some();
+    let mut failures = Vec::new();
+    for example in list_examples() {
+        let options = read_options(&example);
+        let actual = run_example(&example, &options);
+        let expected_path = example.with_extension("expected");
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = match fs::read_to_string(&expected_path) {
+            Ok(expected) => expected,
+            Err(_) => {
+                failures.push(format!("{}: missing {}, run with {}=1",
+                                      example.display(), expected_path.display(), BLESS_ENV));
+                continue;
+            }
+        };
+        if expected != actual {
+            failures.push(format!("{}:\n{}", example.display(), diff(&expected, &actual)));
+        }
+        if !options.lazy {
+            let actual = run_example(&example, &EvalOptions { vm: true, ..options });
+            if expected != actual {
+                failures.push(format!("{} --vm:\n{}", example.display(), diff(&expected, &actual)));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "Snapshot mismatch (- expected, + actual)\n{}", failures.join("\n\n"));
+}